@@ -0,0 +1,10 @@
+CREATE TABLE favorites
+(
+  user_id BIGINT NOT NULL,
+  archive_id BIGINT REFERENCES archives(id) ON DELETE CASCADE,
+  created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+  PRIMARY KEY(user_id, archive_id)
+);
+
+CREATE INDEX favorites_archive_id_idx ON favorites (archive_id);
+CREATE INDEX favorites_user_id_created_at_idx ON favorites (user_id, created_at);
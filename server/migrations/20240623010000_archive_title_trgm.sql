@@ -0,0 +1,3 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX title_trgm_archives_idx ON archives USING GIN(title GIN_TRGM_OPS);
@@ -0,0 +1,2 @@
+ALTER TABLE archive_images ADD is_spread BOOLEAN NULL;
+UPDATE archive_images SET is_spread = width > height WHERE width IS NOT NULL AND height IS NOT NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE archives ADD rating VARCHAR NULL CHECK (rating IN ('safe', 'questionable', 'explicit'));
+
+CREATE INDEX rating_idx ON archives (rating);
@@ -0,0 +1,88 @@
+ALTER TABLE archive_fts ADD description TEXT NULL;
+UPDATE archive_fts SET description = COALESCE((SELECT archives.description FROM archives WHERE archives.id = archive_fts.archive_id), '');
+ALTER TABLE archive_fts ALTER COLUMN description SET NOT NULL;
+ALTER TABLE archive_fts ADD description_tsv TSVECTOR GENERATED ALWAYS AS (SETWEIGHT(TO_TSVECTOR('english', description), 'D')) STORED;
+
+CREATE INDEX description_tsv_archive_fts_idx ON archive_fts USING GIN(description_tsv);
+
+CREATE OR REPLACE FUNCTION update_archive_fts()
+RETURNS TRIGGER AS $$
+BEGIN
+  INSERT INTO archive_fts (
+    archive_id,
+    title,
+    artists,
+    circles,
+    magazines,
+    events,
+    publishers,
+    parodies,
+    tags,
+    description
+  )
+  VALUES (
+    NEW.id,
+    (SELECT archives.title FROM archives WHERE id = NEW.id),
+    (COALESCE((SELECT string_agg(artists.name, ' ') FROM artists INNER JOIN archive_artists r ON r.artist_id = artists.id  WHERE r.archive_id = NEW.id), '')),
+    (COALESCE((SELECT string_agg(circles.name, ' ') FROM circles INNER JOIN archive_circles r ON r.circle_id = circles.id  WHERE r.archive_id = NEW.id), '')),
+    (COALESCE((SELECT string_agg(magazines.name, ' ') FROM magazines INNER JOIN archive_magazines r ON r.magazine_id = magazines.id  WHERE r.archive_id = NEW.id), '')),
+    (COALESCE((SELECT string_agg(events.name, ' ') FROM events INNER JOIN archive_events r ON r.event_id = events.id  WHERE r.archive_id = NEW.id), '')),
+    (COALESCE((SELECT string_agg(publishers.name, ' ') FROM publishers INNER JOIN archive_publishers r ON r.publisher_id = publishers.id  WHERE r.archive_id = NEW.id), '')),
+    (COALESCE((SELECT string_agg(parodies.name, ' ') FROM parodies INNER JOIN archive_parodies r ON r.parody_id = parodies.id  WHERE r.archive_id = NEW.id), '')),
+    (COALESCE((SELECT string_agg(tags.name, ' ') FROM tags INNER JOIN archive_tags r ON r.tag_id = tags.id  WHERE r.archive_id = NEW.id), '')),
+    (SELECT COALESCE(archives.description, '') FROM archives WHERE id = NEW.id)
+  )
+  ON CONFLICT (archive_id) DO UPDATE SET
+    title = EXCLUDED.title,
+    artists = EXCLUDED.artists,
+    magazines = EXCLUDED.magazines,
+    events = EXCLUDED.events,
+    circles = EXCLUDED.circles,
+    publishers = EXCLUDED.publishers,
+    parodies = EXCLUDED.parodies,
+    tags = EXCLUDED.tags,
+    description = EXCLUDED.description;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION update_archive_fts_rela()
+RETURNS TRIGGER AS $$
+BEGIN
+  INSERT INTO archive_fts (
+    archive_id,
+    title,
+    artists,
+    circles,
+    magazines,
+    events,
+    publishers,
+    parodies,
+    tags,
+    description
+  )
+  VALUES (
+    NEW.archive_id,
+    (SELECT archives.title FROM archives WHERE id = NEW.archive_id),
+    (COALESCE((SELECT string_agg(artists.name, ' ') FROM artists INNER JOIN archive_artists r ON r.artist_id = artists.id  WHERE r.archive_id = NEW.archive_id), '')),
+    (COALESCE((SELECT string_agg(circles.name, ' ') FROM circles INNER JOIN archive_circles r ON r.circle_id = circles.id  WHERE r.archive_id = NEW.archive_id), '')),
+    (COALESCE((SELECT string_agg(magazines.name, ' ') FROM magazines INNER JOIN archive_magazines r ON r.magazine_id = magazines.id  WHERE r.archive_id = NEW.archive_id), '')),
+    (COALESCE((SELECT string_agg(events.name, ' ') FROM events INNER JOIN archive_events r ON r.event_id = events.id  WHERE r.archive_id = NEW.archive_id), '')),
+    (COALESCE((SELECT string_agg(publishers.name, ' ') FROM publishers INNER JOIN archive_publishers r ON r.publisher_id = publishers.id  WHERE r.archive_id = NEW.archive_id), '')),
+    (COALESCE((SELECT string_agg(parodies.name, ' ') FROM parodies INNER JOIN archive_parodies r ON r.parody_id = parodies.id  WHERE r.archive_id = NEW.archive_id), '')),
+    (COALESCE((SELECT string_agg(tags.name, ' ') FROM tags INNER JOIN archive_tags r ON r.tag_id = tags.id  WHERE r.archive_id = NEW.archive_id), '')),
+    (SELECT COALESCE(archives.description, '') FROM archives WHERE id = NEW.archive_id)
+  )
+  ON CONFLICT (archive_id) DO UPDATE SET
+    title = EXCLUDED.title,
+    artists = EXCLUDED.artists,
+    magazines = EXCLUDED.magazines,
+    events = EXCLUDED.events,
+    circles = EXCLUDED.circles,
+    publishers = EXCLUDED.publishers,
+    parodies = EXCLUDED.parodies,
+    tags = EXCLUDED.tags,
+    description = EXCLUDED.description;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
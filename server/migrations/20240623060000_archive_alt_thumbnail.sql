@@ -0,0 +1 @@
+ALTER TABLE archives ADD alt_thumbnail SMALLINT NULL;
@@ -0,0 +1,8 @@
+ALTER TABLE archive_fts DROP COLUMN publishers_tsv;
+ALTER TABLE archive_fts ADD publishers_tsv TSVECTOR GENERATED ALWAYS AS (SETWEIGHT(TO_TSVECTOR('english', publishers), 'C')) STORED;
+
+ALTER TABLE archive_fts DROP COLUMN events_tsv;
+ALTER TABLE archive_fts ADD events_tsv TSVECTOR GENERATED ALWAYS AS (SETWEIGHT(TO_TSVECTOR('english', events), 'C')) STORED;
+
+CREATE INDEX publishers_tsv_archive_fts_idx ON archive_fts USING GIN(publishers_tsv);
+CREATE INDEX events_tsv_archive_fts_idx ON archive_fts USING GIN(events_tsv);
@@ -45,6 +45,8 @@ pub enum ApiError {
   #[error(transparent)]
   DatabasError(#[from] sqlx::Error),
   #[error(transparent)]
+  DbError(#[from] db::DbError),
+  #[error(transparent)]
   IoError(#[from] io::Error),
   #[error(transparent)]
   ZipError(#[from] async_zip::error::ZipError),
@@ -52,6 +54,8 @@ pub enum ApiError {
   ImageNotFound,
   #[error("404")]
   NotFound,
+  #[error("401")]
+  Unauthorized,
 }
 
 impl IntoResponse for ApiError {
@@ -74,6 +78,18 @@ impl IntoResponse for ApiError {
           "Something went wrong".to_owned(),
         )
       }
+      ApiError::DbError(err) => match err {
+        db::DbError::NotFound => (StatusCode::NOT_FOUND, "Resource not found".to_string()),
+        db::DbError::Conflict { .. } => (StatusCode::CONFLICT, err.to_string()),
+        db::DbError::Validation(message) => (StatusCode::BAD_REQUEST, message),
+        db::DbError::Backend(err) => {
+          error!(%err, "database error");
+          (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Something went wrong".to_owned(),
+          )
+        }
+      },
       ApiError::IoError(err) => {
         error!(%err, "file error");
         (
@@ -93,6 +109,7 @@ impl IntoResponse for ApiError {
         "Image not found in archive".to_string(),
       ),
       ApiError::NotFound => (StatusCode::NOT_FOUND, "Resource not found".to_string()),
+      ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, "Unauthorized".to_string()),
     };
 
     (status, ApiJson(ErrorResponse { message })).into_response()
@@ -101,6 +118,7 @@ impl IntoResponse for ApiError {
 
 pub async fn start_server() -> anyhow::Result<()> {
   crate::log::server_logging();
+  crate::config::validate_config()?;
 
   info!(target: "server::config", "Server config\n{}", *CONFIG);
 
@@ -114,6 +132,13 @@ pub async fn start_server() -> anyhow::Result<()> {
 
   let app = Router::new()
     .route("/library", get(routes::library))
+    .route("/library/years", get(routes::release_years))
+    .route("/library/languages", get(routes::languages))
+    .route("/library/suggest", get(routes::suggest))
+    .route("/library/recent", get(routes::recent))
+    .route("/library/random", get(routes::random))
+    .route("/admin/library", get(routes::admin_library))
+    .route("/admin/diff/:a/:b", get(routes::admin_diff))
     .route("/archive/:id", get(routes::archive_data))
     .merge(image::get_routes())
     .layer(cors)
@@ -1,6 +1,7 @@
 use crate::db;
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Serialize)]
 pub struct Archive {
@@ -13,6 +14,8 @@ pub struct Archive {
   pub size: i64,
   pub cover: Option<ImageDimensions>,
   pub thumbnail: i16,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rating: Option<String>,
   pub images: Vec<Image>,
   pub created_at: NaiveDateTime,
   pub released_at: NaiveDateTime,
@@ -30,6 +33,7 @@ impl From<db::Archive> for Archive {
       size,
       cover,
       thumbnail,
+      rating,
       images,
       created_at,
       released_at,
@@ -45,6 +49,7 @@ impl From<db::Archive> for Archive {
       size,
       cover,
       thumbnail,
+      rating,
       images,
       created_at,
       released_at,
@@ -79,6 +84,12 @@ pub struct ArchiveData {
   #[serde(skip_serializing_if = "Option::is_none")]
   pub cover: Option<ImageDimensions>,
   pub thumbnail: i16,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub alt_cover: Option<ImageDimensions>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub alt_thumbnail: Option<i16>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rating: Option<String>,
   pub images: Vec<Image>,
   pub created_at: NaiveDateTime,
   pub released_at: NaiveDateTime,
@@ -112,6 +123,9 @@ impl From<db::ArchiveRelations> for ArchiveData {
       size,
       cover,
       thumbnail,
+      alt_cover,
+      alt_thumbnail,
+      rating,
       images,
       created_at,
       released_at,
@@ -135,6 +149,9 @@ impl From<db::ArchiveRelations> for ArchiveData {
       size,
       cover,
       thumbnail,
+      alt_cover,
+      alt_thumbnail,
+      rating,
       images,
       created_at,
       released_at,
@@ -205,6 +222,8 @@ pub struct ArchiveListItem {
   pub title: String,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub cover: Option<ImageDimensions>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rating: Option<String>,
   #[serde(skip_serializing_if = "<[_]>::is_empty")]
   pub artists: Vec<Taxonomy>,
   #[serde(skip_serializing_if = "<[_]>::is_empty")]
@@ -221,7 +240,7 @@ pub struct ArchiveListItem {
   pub tags: Vec<Taxonomy>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ImageDimensions {
   #[serde(skip_serializing_if = "Option::is_none")]
   pub width: Option<i16>,
@@ -237,6 +256,8 @@ pub struct Image {
   pub width: Option<i16>,
   #[serde(skip_serializing_if = "Option::is_none")]
   pub height: Option<i16>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub is_spread: Option<bool>,
 }
 
 #[derive(Serialize)]
@@ -245,4 +266,99 @@ pub struct LibraryPage {
   pub page: usize,
   pub limit: usize,
   pub total: i64,
+  #[serde(skip_serializing_if = "std::ops::Not::not")]
+  pub capped: bool,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub facets: Option<HashMap<String, Vec<FacetItem>>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub debug: Option<SearchDebug>,
+}
+
+#[derive(Serialize)]
+pub struct FacetItem {
+  pub id: i64,
+  pub slug: String,
+  pub name: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub namespace: Option<String>,
+  pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct YearFacet {
+  pub year: i32,
+  pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct LanguageCount {
+  pub language: String,
+  pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct SearchDebug {
+  pub parsed: String,
+  pub filters: Vec<String>,
+  pub phrases: Vec<String>,
+}
+
+/// Values of a field that differs between two archives.
+#[derive(Serialize)]
+pub struct FieldDiff<T> {
+  pub a: T,
+  pub b: T,
+}
+
+impl<T: PartialEq> FieldDiff<T> {
+  pub fn compare(a: T, b: T) -> Option<Self> {
+    (a != b).then_some(FieldDiff { a, b })
+  }
+}
+
+#[derive(Serialize, Default)]
+pub struct SetDiff {
+  pub only_a: Vec<String>,
+  pub only_b: Vec<String>,
+  pub shared: Vec<String>,
+}
+
+impl SetDiff {
+  pub fn new(a: Vec<String>, b: Vec<String>) -> Self {
+    let mut diff = SetDiff::default();
+
+    for item in &a {
+      if b.contains(item) {
+        diff.shared.push(item.clone());
+      } else {
+        diff.only_a.push(item.clone());
+      }
+    }
+
+    diff.only_b = b.into_iter().filter(|item| !a.contains(item)).collect();
+
+    diff
+  }
+}
+
+/// Differences between two archives. Fields are only included when they differ, taxonomies are
+/// compared by slug and sources by URL, or name when they don't have one.
+#[derive(Serialize)]
+pub struct ArchiveDiff {
+  pub a: i64,
+  pub b: i64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub title: Option<FieldDiff<String>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub pages: Option<FieldDiff<Option<i16>>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub size: Option<FieldDiff<i64>>,
+  pub artists: SetDiff,
+  pub circles: SetDiff,
+  pub magazines: SetDiff,
+  pub events: SetDiff,
+  pub publishers: SetDiff,
+  pub parodies: SetDiff,
+  pub tags: SetDiff,
+  pub sources: SetDiff,
 }
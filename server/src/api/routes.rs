@@ -1,10 +1,18 @@
 use super::{
-  models::{ArchiveData, LibraryPage},
+  models::{
+    ArchiveData, ArchiveDiff, ArchiveId, ArchiveListItem, FacetItem, LanguageCount, LibraryPage,
+    YearFacet,
+  },
   ApiError, ApiJson, AppState,
 };
-use crate::db;
+use crate::{config::CONFIG, db, utils};
 use anyhow::anyhow;
-use axum::extract::{Path, Query, State};
+use axum::{
+  extract::{Path, Query, State},
+  http::{header, HeaderMap},
+};
+use serde::Deserialize;
+use sqlx::PgPool;
 use std::{collections::HashMap, fmt::Display, str::FromStr};
 
 pub struct SearchQuery {
@@ -12,6 +20,16 @@ pub struct SearchQuery {
   pub page: usize,
   pub sort: Sorting,
   pub order: Ordering,
+  pub favorited_by: Option<i64>,
+  pub explain: bool,
+  pub include_deleted: bool,
+  pub only_deleted: bool,
+  pub seed: Option<String>,
+  pub facets: bool,
+  /// `keyword:slug` taxonomies whose archives are excluded, like `artist:foo` or `female:bar`
+  pub blacklist: Vec<String>,
+  pub match_mode: MatchMode,
+  pub projection: SearchProjection,
 }
 
 impl Display for Ordering {
@@ -23,12 +41,18 @@ impl Display for Ordering {
   }
 }
 
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Sorting {
   Relevance,
   ReleasedAt,
   CreatedAt,
   Title,
+  /// By the alphabetically first artist name
+  Artist,
   Pages,
+  Favorited,
+  Random,
 }
 
 impl Default for Sorting {
@@ -49,7 +73,10 @@ impl FromStr for Sorting {
       "released_at" => Ok(Self::ReleasedAt),
       "created_at" => Ok(Self::CreatedAt),
       "title" => Ok(Self::Title),
+      "artist" => Ok(Self::Artist),
       "pages" => Ok(Self::Pages),
+      "favorited" => Ok(Self::Favorited),
+      "random" => Ok(Self::Random),
       _ => Err(anyhow!("Invalid sort value '{s}'")),
     }
   }
@@ -81,11 +108,65 @@ impl FromStr for Ordering {
   }
 }
 
-pub async fn library(
-  Query(params): Query<HashMap<String, String>>,
-  State(state): State<AppState>,
-) -> Result<ApiJson<LibraryPage>, ApiError> {
-  let search_query = SearchQuery {
+/// How the words of a free-text search are combined.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum MatchMode {
+  /// Every word must match.
+  #[default]
+  All,
+  /// Any of the words must match.
+  Any,
+}
+
+impl FromStr for MatchMode {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.to_lowercase();
+    let s = s.as_str();
+
+    match s {
+      "all" => Ok(Self::All),
+      "any" => Ok(Self::Any),
+      _ => Err(anyhow!("Invalid match mode '{s}'")),
+    }
+  }
+}
+
+/// How much of each archive is returned in the search results.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum SearchProjection {
+  /// Every taxonomy.
+  #[default]
+  Full,
+  /// Only the artists, enough for a grid of covers.
+  Minimal,
+}
+
+impl FromStr for SearchProjection {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.to_lowercase();
+    let s = s.as_str();
+
+    match s {
+      "full" => Ok(Self::Full),
+      "minimal" => Ok(Self::Minimal),
+      _ => Err(anyhow!("Invalid projection '{s}'")),
+    }
+  }
+}
+
+fn parse_flag(params: &HashMap<String, String>, key: &str) -> bool {
+  params
+    .get(key)
+    .map(|value| value == "true" || value == "1")
+    .unwrap_or_default()
+}
+
+fn search_query(params: &HashMap<String, String>) -> SearchQuery {
+  SearchQuery {
     value: params.get("q").cloned().unwrap_or_default(),
     page: {
       if let Some(page) = params.get("page") {
@@ -108,16 +189,205 @@ pub async fn library(
         Ordering::default()
       }
     },
-  };
+    favorited_by: params
+      .get("favorited_by")
+      .and_then(|user_id| user_id.parse().ok()),
+    explain: parse_flag(params, "explain"),
+    include_deleted: false,
+    only_deleted: false,
+    seed: params.get("seed").map(|seed| match seed.as_str() {
+      "daily" => utils::daily_seed(),
+      _ => seed.to_string(),
+    }),
+    facets: parse_flag(params, "facets"),
+    blacklist: params
+      .get("blacklist")
+      .map(|blacklist| {
+        blacklist
+          .split(',')
+          .map(|entry| entry.trim().to_string())
+          .filter(|entry| !entry.is_empty())
+          .collect()
+      })
+      .unwrap_or_default(),
+    match_mode: params
+      .get("match")
+      .and_then(|mode| mode.parse().ok())
+      .unwrap_or_default(),
+    projection: params
+      .get("projection")
+      .and_then(|projection| projection.parse().ok())
+      .unwrap_or_default(),
+  }
+}
+
+async fn search_page(search_query: SearchQuery, pool: &PgPool) -> Result<LibraryPage, ApiError> {
+  let results = db::search(&search_query, pool).await?;
 
-  let (archives, total) = db::search(&search_query, &state.pool).await?;
+  let facets = if search_query.facets {
+    Some(
+      db::taxonomy_facets(&search_query, pool)
+        .await?
+        .into_iter()
+        .map(|(tag_type, items)| {
+          (
+            tag_type.to_string(),
+            items
+              .into_iter()
+              .map(|facet| FacetItem {
+                id: facet.taxonomy.id,
+                slug: facet.taxonomy.slug,
+                name: facet.taxonomy.name,
+                namespace: facet.namespace,
+                count: facet.count,
+              })
+              .collect(),
+          )
+        })
+        .collect(),
+    )
+  } else {
+    None
+  };
 
-  Ok(ApiJson(LibraryPage {
-    archives,
+  Ok(LibraryPage {
+    archives: results.archives,
     page: search_query.page,
     limit: 24,
-    total,
-  }))
+    total: results.total,
+    capped: results.capped,
+    facets,
+    debug: search_query
+      .explain
+      .then(|| db::explain_search(&search_query)),
+  })
+}
+
+pub async fn library(
+  Query(params): Query<HashMap<String, String>>,
+  State(state): State<AppState>,
+) -> Result<ApiJson<LibraryPage>, ApiError> {
+  let search_query = search_query(&params);
+
+  Ok(ApiJson(search_page(search_query, &state.pool).await?))
+}
+
+/// Checks the request carries the configured admin token.
+fn check_admin(headers: &HeaderMap) -> Result<(), ApiError> {
+  let token = headers
+    .get(header::AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "));
+
+  match (&CONFIG.server.admin_token, token) {
+    (Some(admin_token), Some(token)) if admin_token == token => Ok(()),
+    _ => Err(ApiError::Unauthorized),
+  }
+}
+
+/// Same as `library` but can also list soft-deleted archives. Requires the configured admin token.
+pub async fn admin_library(
+  headers: HeaderMap,
+  Query(params): Query<HashMap<String, String>>,
+  State(state): State<AppState>,
+) -> Result<ApiJson<LibraryPage>, ApiError> {
+  check_admin(&headers)?;
+
+  let mut search_query = search_query(&params);
+  search_query.include_deleted = parse_flag(&params, "include_deleted");
+  search_query.only_deleted = parse_flag(&params, "only_deleted");
+
+  Ok(ApiJson(search_page(search_query, &state.pool).await?))
+}
+
+pub async fn admin_diff(
+  headers: HeaderMap,
+  Path((a, b)): Path<(i64, i64)>,
+  State(state): State<AppState>,
+) -> Result<ApiJson<ArchiveDiff>, ApiError> {
+  check_admin(&headers)?;
+
+  let diff = db::diff_archives(a, b, &state.pool)
+    .await
+    .map_err(db::DbError::from)?;
+
+  Ok(ApiJson(diff))
+}
+
+pub async fn release_years(
+  Query(params): Query<HashMap<String, String>>,
+  State(state): State<AppState>,
+) -> Result<ApiJson<Vec<YearFacet>>, ApiError> {
+  let facets = db::release_year_facets(&search_query(&params), &state.pool).await?;
+
+  Ok(ApiJson(
+    facets
+      .into_iter()
+      .map(|(year, count)| YearFacet { year, count })
+      .collect(),
+  ))
+}
+
+/// Languages of the published archives, most used first. `none=true` also counts the archives
+/// without a language.
+pub async fn languages(
+  Query(params): Query<HashMap<String, String>>,
+  State(state): State<AppState>,
+) -> Result<ApiJson<Vec<LanguageCount>>, ApiError> {
+  let languages = db::distinct_languages(parse_flag(&params, "none"), &state.pool).await?;
+
+  Ok(ApiJson(
+    languages
+      .into_iter()
+      .map(|(language, count)| LanguageCount { language, count })
+      .collect(),
+  ))
+}
+
+pub async fn recent(
+  Query(params): Query<HashMap<String, String>>,
+  State(state): State<AppState>,
+) -> Result<ApiJson<Vec<ArchiveListItem>>, ApiError> {
+  let limit = params
+    .get("limit")
+    .and_then(|limit| limit.parse::<i64>().ok())
+    .unwrap_or(12)
+    .clamp(1, 48);
+
+  let archives = db::recent_archives(limit, &state.pool).await?;
+
+  Ok(ApiJson(archives))
+}
+
+/// One archive picked from the search results by the `seed`, the daily seed by default.
+pub async fn random(
+  Query(params): Query<HashMap<String, String>>,
+  State(state): State<AppState>,
+) -> Result<ApiJson<ArchiveId>, ApiError> {
+  let search_query = search_query(&params);
+  let seed = search_query.seed.clone().unwrap_or_else(utils::daily_seed);
+
+  let archive = db::random_archive(&seed, &search_query, &state.pool)
+    .await?
+    .ok_or(ApiError::NotFound)?;
+
+  Ok(ApiJson(archive.into()))
+}
+
+pub async fn suggest(
+  Query(params): Query<HashMap<String, String>>,
+  State(state): State<AppState>,
+) -> Result<ApiJson<Vec<String>>, ApiError> {
+  let partial = params.get("q").map(|q| q.as_str()).unwrap_or_default();
+  let limit = params
+    .get("limit")
+    .and_then(|limit| limit.parse::<i64>().ok())
+    .unwrap_or(10)
+    .clamp(1, 50);
+
+  let suggestions = db::suggest_query_tokens(partial, limit, &state.pool).await?;
+
+  Ok(ApiJson(suggestions))
 }
 
 pub async fn archive_data(
@@ -126,9 +396,5 @@ pub async fn archive_data(
 ) -> Result<ApiJson<ArchiveData>, ApiError> {
   let archive = db::fetch_archive_data(&state.pool, id).await?;
 
-  if let Some(archive) = archive {
-    Ok(ApiJson(archive.into()))
-  } else {
-    Err(ApiError::NotFound)
-  }
+  Ok(ApiJson(archive.into()))
 }
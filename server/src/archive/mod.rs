@@ -9,12 +9,11 @@ use data_encoding::HEXUPPER;
 use images::{calculate_dimensions, generate_thumbnails, ThumbnailOpts};
 use indicatif::MultiProgress;
 use itertools::Itertools;
-use slug::slugify;
 use sqlx::PgPool;
 use std::{
   fs,
   io::{Cursor, Read},
-  path::Path,
+  path::{Path, PathBuf},
 };
 use thiserror::Error;
 use tracing::{info, warn};
@@ -105,12 +104,20 @@ pub fn get_zip_files(
   Ok(files)
 }
 
-pub async fn index(
+/// An archive read from disk, with the data to save it.
+pub struct PreparedArchive {
+  pub path: PathBuf,
+  pub data: db::UpsertArchiveData,
+}
+
+/// Reads the archive at the given path and its metadata, without saving it. Returns `None` when
+/// the archive is already indexed and reindexing wasn't requested.
+pub async fn prepare(
   path: &Path,
-  opts: IndexOptions,
+  opts: &IndexOptions,
   pool: &PgPool,
   mp: &MultiProgress,
-) -> anyhow::Result<bool> {
+) -> anyhow::Result<Option<PreparedArchive>> {
   if !opts.reindex
     && sqlx::query_scalar!(
       r#"SELECT id FROM archives WHERE path = $1 AND deleted_at IS NULL"#,
@@ -120,7 +127,7 @@ pub async fn index(
     .await?
     .is_some()
   {
-    return Ok(false);
+    return Ok(None);
   }
 
   mp.suspend(|| info!("Indexing '{}'", path.to_string()));
@@ -178,7 +185,7 @@ pub async fn index(
     archive_data.title = Some(filename);
   }
 
-  archive_data.slug = archive_data.title.as_ref().map(slugify);
+  archive_data.slug = archive_data.title.as_ref().map(utils::slugify);
 
   if archive_data.released_at.is_none() {
     if let Some(zip_date) = file
@@ -213,24 +220,32 @@ pub async fn index(
     archive_data.thumbnail = Some(1);
   }
 
-  let thumbnail = archive_data.thumbnail;
-
-  let images = archive_data.images.as_ref().map(|images| {
-    images
-      .iter()
-      .map(|image| (image.page_number, image.filename.clone()))
-      .collect_vec()
-  });
+  Ok(Some(PreparedArchive {
+    path: path.to_path_buf(),
+    data: archive_data,
+  }))
+}
 
-  let archive_id = db::upsert_archive(archive_data, pool, mp).await?;
+/// Calculates the image dimensions and generates the thumbnails of a saved archive, as requested
+/// by the options. The archive is read again, so a batch doesn't keep every file in memory.
+pub async fn finish(
+  archive: &PreparedArchive,
+  archive_id: i64,
+  opts: &IndexOptions,
+  pool: &PgPool,
+  mp: &MultiProgress,
+) -> anyhow::Result<()> {
+  let path = &archive.path;
 
-  if let (Some(images), Some(thumbnail)) = (images, thumbnail) {
+  if let (Some(images), Some(thumbnail)) = (&archive.data.images, archive.data.thumbnail) {
     if opts.dimensions || opts.thumbnails {
+      let ZipArchiveData { mut file, .. } = read_zip(path)?;
+
       let mut files = get_zip_files(
         images
-          .into_iter()
-          .sorted_by_key(|image| image.0)
-          .map(|image| image.1)
+          .iter()
+          .sorted_by_key(|image| image.page_number)
+          .map(|image| image.filename.clone())
           .collect(),
         &mut file,
       )?;
@@ -253,5 +268,5 @@ pub async fn index(
 
   mp.suspend(|| info!("Indexed '{}' with ID {}", path.to_string(), archive_id));
 
-  Ok(true)
+  Ok(())
 }
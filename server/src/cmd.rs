@@ -1,12 +1,15 @@
 use crate::archive::ZipArchiveData;
 use crate::db::ArchiveFile;
 use crate::image::ImageCodec;
-use crate::{archive, config::CONFIG, db};
+use crate::{api, archive, config::CONFIG, db};
 use crate::{scraper, torrents, utils};
 use clap::{Args, Parser, Subcommand};
 use funty::Fundamental;
+use futures_util::stream::TryStreamExt;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use sqlx::{PgPool, QueryBuilder};
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 use std::time::{Duration, Instant};
 use tokio::time::sleep;
@@ -36,6 +39,32 @@ pub enum Commands {
   Publish(PublishArgs),
   #[command(about="Hide given archives from the search results.", long_about = None)]
   Unpublish(PublishArgs),
+  #[command(about="Add tags to the given archives, keeping their other tags.", long_about = None)]
+  AddTags(BulkTagsArgs),
+  #[command(about="Remove tags from the given archives.", long_about = None)]
+  RemoveTags(BulkTagsArgs),
+  #[command(about="Canonicalize the namespaces of existing archive tags.", long_about = None)]
+  NormalizeNamespaces,
+  #[command(about="Move the archive tags of a namespace to another one.", long_about = None)]
+  ReassignNamespace(ReassignNamespaceArgs),
+  #[command(about="Merge archive sources whose names only differ in case.", long_about = None)]
+  CollapseSources,
+  #[command(about="Compute the hash of archives that don't have a real one.", long_about = None)]
+  FillHashes,
+  #[command(about="Fix page counts that don't match the archive images.", long_about = None)]
+  RecomputePages,
+  #[command(about="Rebuild the search index for archives.", long_about = None)]
+  RebuildFts(RebuildFtsArgs),
+  #[command(about="Set the cover of an archive to the page with the given filename.", long_about = None)]
+  SetCover(SetCoverArgs),
+  #[command(about="Generate the slug of an archive again from its title.", long_about = None)]
+  RegenerateSlug(RegenerateSlugArgs),
+  #[command(about="Find the archives with a source pointing to the given URL.", long_about = None)]
+  FindSource(FindSourceArgs),
+  #[command(about="Copy an archive and its metadata for another file.", long_about = None)]
+  Duplicate(DuplicateArgs),
+  #[command(about="Export all archives with their metadata as JSON lines.", long_about = None)]
+  Export(ExportArgs),
 }
 
 #[derive(Args, Clone)]
@@ -140,6 +169,12 @@ pub struct ScrapeArgs {
     help = "Miliseconds to wait between archives"
   )]
   pub sleep: u64,
+  #[arg(
+    long,
+    default_value = "false",
+    help = "Report what would be updated without writing to the database"
+  )]
+  pub dry_run: bool,
 }
 
 #[derive(Args, Clone)]
@@ -148,6 +183,76 @@ pub struct PublishArgs {
   pub id: String,
 }
 
+#[derive(Args, Clone)]
+pub struct BulkTagsArgs {
+  #[arg(help = "List of archive IDs or range (ex: 1-10,14,230-400)")]
+  pub id: String,
+  #[arg(
+    required = true,
+    help = "Tags as 'namespace:name', or just 'name' without a namespace"
+  )]
+  pub tags: Vec<String>,
+}
+
+#[derive(Args, Clone)]
+pub struct ReassignNamespaceArgs {
+  #[arg(help = "Namespace to move the tags from")]
+  pub from: String,
+  #[arg(help = "Namespace to move the tags to")]
+  pub to: String,
+}
+
+#[derive(Args, Clone)]
+pub struct RebuildFtsArgs {
+  #[arg(long, help = "Archive ID to rebuild. Defaults to all archives")]
+  pub id: Option<i64>,
+  #[arg(
+    long,
+    default_value = "false",
+    conflicts_with = "id",
+    help = "Only index archives missing from the search index"
+  )]
+  pub missing: bool,
+}
+
+#[derive(Args, Clone)]
+pub struct SetCoverArgs {
+  #[arg(help = "Archive ID")]
+  pub id: i64,
+  #[arg(help = "Filename of the page to use as cover")]
+  pub filename: String,
+}
+
+#[derive(Args, Clone)]
+pub struct RegenerateSlugArgs {
+  #[arg(help = "Archive ID")]
+  pub id: i64,
+}
+
+#[derive(Args, Clone)]
+pub struct FindSourceArgs {
+  #[arg(help = "Source URL")]
+  pub url: String,
+  #[arg(long, default_value = "false", help = "Include unpublished archives")]
+  pub include_deleted: bool,
+}
+
+#[derive(Args, Clone)]
+pub struct DuplicateArgs {
+  #[arg(help = "Archive ID")]
+  pub id: i64,
+  #[arg(help = "Path to the archive file of the copy")]
+  pub path: PathBuf,
+}
+
+#[derive(Args, Clone)]
+pub struct ExportArgs {
+  #[arg(help = "File to write the archives to")]
+  pub path: PathBuf,
+  #[arg(long, default_value = "false", help = "Include unpublished archives")]
+  pub include_deleted: bool,
+}
+
 async fn fetch_archives(
   pool: &PgPool,
   id_ranges: &Option<String>,
@@ -250,30 +355,31 @@ pub async fn index(mut args: IndexArgs) -> anyhow::Result<()> {
 
   let start = Instant::now();
 
-  for path in paths_to_index {
-    match archive::index(
-      &path,
-      archive::IndexOptions {
-        reindex: args.reindex,
-        dimensions: args.dimensions,
-        thumbnails: args.thumbnails,
-      },
-      &pool,
-      &mp,
-    )
-    .await
-    {
-      Ok(indexed) => {
-        if indexed {
-          count += 1
-        }
-      }
+  let opts = archive::IndexOptions {
+    reindex: args.reindex,
+    dimensions: args.dimensions,
+    thumbnails: args.thumbnails,
+  };
+  let batch_size = CONFIG.database.batch_size.max(1);
+  let mut batch = vec![];
+  let mut paths_to_index = paths_to_index.into_iter().peekable();
+
+  while let Some(path) = paths_to_index.next() {
+    match archive::prepare(&path, &opts, &pool, &mp).await {
+      Ok(Some(archive)) => batch.push(archive),
+      Ok(None) => {}
       Err(err) => pb.suspend(
         || error!(target: "cmd::index", "Failed to index archive '{}' - {err}", path.display()),
       ),
     }
 
-    pb.inc(1)
+    pb.inc(1);
+
+    if batch.len() < batch_size && paths_to_index.peek().is_some() {
+      continue;
+    }
+
+    count += save_batch(std::mem::take(&mut batch), &opts, &pool, &mp).await;
   }
 
   pb.finish_and_clear();
@@ -285,6 +391,72 @@ pub async fn index(mut args: IndexArgs) -> anyhow::Result<()> {
   Ok(())
 }
 
+/// Saves a batch of prepared archives in one transaction. When it fails, the archives are saved
+/// one by one instead, so only the failing ones are skipped. Returns the amount of saved archives.
+async fn save_batch(
+  batch: Vec<archive::PreparedArchive>,
+  opts: &archive::IndexOptions,
+  pool: &PgPool,
+  mp: &MultiProgress,
+) -> usize {
+  if batch.is_empty() {
+    return 0;
+  }
+
+  let data = batch.iter().map(|archive| archive.data.clone()).collect();
+
+  let ids = match db::upsert_archives(data, pool, mp).await {
+    Ok(ids) => ids.into_iter().map(Some).collect::<Vec<_>>(),
+    Err(err) => {
+      mp.suspend(|| {
+        error!(target: "cmd::index", "Failed to save a batch of archives, saving them one by one - {err}")
+      });
+
+      let mut ids = vec![];
+
+      for archive in &batch {
+        match db::upsert_archive(archive.data.clone(), pool, mp).await {
+          Ok(archive) => ids.push(Some(archive.id)),
+          Err(err) => {
+            mp.suspend(|| {
+              error!(
+                target: "cmd::index",
+                "Failed to index archive '{}' - {err}",
+                archive.path.display()
+              )
+            });
+            ids.push(None);
+          }
+        }
+      }
+
+      ids
+    }
+  };
+
+  let mut count = 0;
+
+  for (archive, id) in batch.iter().zip(ids) {
+    let Some(id) = id else {
+      continue;
+    };
+
+    count += 1;
+
+    if let Err(err) = archive::finish(archive, id, opts, pool, mp).await {
+      mp.suspend(|| {
+        error!(
+          target: "cmd::index",
+          "Failed to process archive '{}' - {err}",
+          archive.path.display()
+        )
+      });
+    }
+  }
+
+  count
+}
+
 pub async fn index_torrents(args: IndexTorrentArsgs) -> anyhow::Result<()> {
   let paths = args
     .paths
@@ -457,7 +629,7 @@ pub async fn scrape(args: ScrapeArgs) -> anyhow::Result<()> {
   for id in archives {
     mp.suspend(|| info!(target: "archive::scrape", "Scraping metadata for archive ID {}", id));
 
-    if let Err(err) = scraper::scrape(id, args.site, &pool, &mp).await {
+    if let Err(err) = scraper::scrape(id, args.site, args.dry_run, &pool, &mp).await {
       mp.suspend(|| error!("Failed to scrape metadata for archive ID {id}: {err}"));
     }
 
@@ -490,3 +662,195 @@ pub async fn pusblish(args: PublishArgs, publish: bool) -> anyhow::Result<()> {
 
   Ok(())
 }
+
+pub async fn bulk_tags(args: BulkTagsArgs, add: bool) -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+
+  let ids = fetch_archives(&pool, &Some(args.id))
+    .await?
+    .into_iter()
+    .map(|archive| archive.id)
+    .collect::<Vec<_>>();
+
+  let tags = args
+    .tags
+    .into_iter()
+    .map(|tag| match tag.split_once(':') {
+      Some((namespace, name)) => (name.to_string(), namespace.to_string()),
+      None => (tag, "".to_string()),
+    })
+    .collect::<Vec<_>>();
+
+  if add {
+    db::bulk_add_tags(&ids, tags, &pool).await?;
+  } else {
+    db::bulk_remove_tags(&ids, tags, &pool).await?;
+  }
+
+  info!("{} archives updated", ids.len());
+
+  Ok(())
+}
+
+pub async fn normalize_namespaces() -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let affected = db::normalize_tag_namespaces(&pool).await?;
+
+  info!("{affected} archive tags updated");
+
+  Ok(())
+}
+
+pub async fn reassign_namespace(args: ReassignNamespaceArgs) -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let affected = db::reassign_namespace(&args.from, &args.to, &pool).await?;
+
+  info!("{affected} archive tags updated");
+
+  Ok(())
+}
+
+pub async fn fill_hashes() -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let archives = db::archives_without_real_hash(&pool).await?;
+  let mut count = 0;
+
+  for (archive, path) in archives {
+    let hash = match archive::read_zip(&path) {
+      Ok(ZipArchiveData { hash, .. }) => hash,
+      Err(err) => {
+        error!("Couldn't read archive ID {} at '{path}': {err}", archive.id);
+        continue;
+      }
+    };
+
+    match db::set_archive_hash(archive.id, &hash, &pool).await {
+      Ok(()) => count += 1,
+      Err(db::DbError::Conflict {
+        archive_id: Some(other_id),
+        ..
+      }) => {
+        error!(
+          "Archive ID {} has the same hash as archive ID {other_id}. Skipping",
+          archive.id
+        );
+      }
+      Err(err) => return Err(err.into()),
+    }
+  }
+
+  info!("{count} archive hashes updated");
+
+  Ok(())
+}
+
+pub async fn collapse_sources() -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let affected = db::collapse_source_duplicates(&pool).await?;
+
+  info!("{affected} duplicated archive sources removed");
+
+  Ok(())
+}
+
+pub async fn recompute_pages() -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let affected = db::recompute_page_counts(&pool).await?;
+
+  info!("{affected} archive page counts corrected");
+
+  Ok(())
+}
+
+pub async fn rebuild_fts(args: RebuildFtsArgs) -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let affected = if args.missing {
+    let missing = db::archives_missing_fts(&pool).await?;
+
+    for archive in &missing {
+      info!(
+        "Archive ID {} ({}) is missing from the search index",
+        archive.id, archive.slug
+      );
+    }
+
+    db::repair_fts(&pool).await?
+  } else {
+    db::rebuild_fts(args.id, &pool).await?
+  };
+
+  info!("{affected} archives reindexed");
+
+  Ok(())
+}
+
+pub async fn set_cover(args: SetCoverArgs) -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let page = db::set_cover_by_filename(args.id, &args.filename, &pool).await?;
+
+  info!("Cover for archive ID {} set to page {page}", args.id);
+
+  Ok(())
+}
+
+pub async fn regenerate_slug(args: RegenerateSlugArgs) -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let (old_slug, slug) = db::regenerate_slug(args.id, &pool).await?;
+
+  if old_slug == slug {
+    info!("Slug for archive ID {} is already '{slug}'", args.id);
+  } else {
+    info!(
+      "Slug for archive ID {} changed from '{old_slug}' to '{slug}'",
+      args.id
+    );
+  }
+
+  Ok(())
+}
+
+pub async fn find_source(args: FindSourceArgs) -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let archives = db::find_archives_by_source_url(&args.url, args.include_deleted, &pool).await?;
+
+  if archives.is_empty() {
+    info!("No archives found with the source '{}'", args.url);
+  }
+
+  for archive in archives {
+    info!("Archive ID {} ({})", archive.id, archive.slug);
+  }
+
+  Ok(())
+}
+
+pub async fn duplicate(args: DuplicateArgs) -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let ZipArchiveData { hash, .. } = archive::read_zip(&args.path)?;
+  let path = args.path.canonicalize()?.to_string_lossy().to_string();
+
+  let new_id = db::duplicate_archive(args.id, hash, path, &pool).await?;
+
+  info!("Archive ID {} copied as archive ID {new_id}", args.id);
+
+  Ok(())
+}
+
+pub async fn export(args: ExportArgs) -> anyhow::Result<()> {
+  let pool = db::get_pool().await?;
+  let mut writer = BufWriter::new(File::create(&args.path)?);
+  let mut archives = std::pin::pin!(db::stream_all_archives(args.include_deleted, &pool));
+  let mut count = 0;
+
+  while let Some(archive) = archives.try_next().await? {
+    serde_json::to_writer(&mut writer, &api::models::ArchiveData::from(archive))?;
+    writer.write_all(b"\n")?;
+    count += 1;
+  }
+
+  writer.flush()?;
+
+  info!("{count} archives exported to {}", args.path.display());
+
+  Ok(())
+}
@@ -1,11 +1,37 @@
-use crate::image;
+use crate::{api::routes::Sorting, db, image, utils::SlugStrategy};
+use anyhow::anyhow;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use serde_inline_default::serde_inline_default;
-use std::{env, fmt::Display, fs, path::PathBuf};
+use std::{
+  collections::HashMap,
+  env,
+  fmt::{Debug, Display},
+  fs,
+  path::PathBuf,
+};
 
 pub static CONFIG: Lazy<Config> = Lazy::new(|| {
+  // Tests use the defaults instead of a configuration file
+  if cfg!(test) {
+    let mut config: Config = toml::from_str(
+      r#"[database]
+host = "127.0.0.1"
+port = 5432
+name = "faccina"
+user = "faccina"
+pass = "faccina"
+
+[directories]
+create_links = false"#,
+    )
+    .unwrap();
+    config.directories.set_relative();
+
+    return config;
+  }
+
   let config_location = env::var("CONFIG_FILE").unwrap_or("config.toml".to_string());
   let file = fs::read_to_string(config_location).expect("Failed to read read configuration file");
   let mut config: Config = toml::from_str(&file)
@@ -17,6 +43,72 @@ pub static CONFIG: Lazy<Config> = Lazy::new(|| {
   config
 });
 
+/// Checks the configured directories before any work starts, so a misconfiguration isn't found
+/// halfway through an import.
+pub fn validate_config() -> anyhow::Result<()> {
+  let links = &CONFIG.directories.links;
+
+  if CONFIG.directories.create_links {
+    fs::create_dir_all(links).map_err(|err| {
+      anyhow!(
+        "Couldn't create the symbolic links directory '{}': {err}",
+        links.display()
+      )
+    })?;
+
+    let probe = links.join(".write_test");
+
+    fs::write(&probe, b"")
+      .and_then(|_| fs::remove_file(&probe))
+      .map_err(|err| {
+        anyhow!(
+          "The symbolic links directory '{}' is not writable: {err}",
+          links.display()
+        )
+      })?;
+  }
+
+  if CONFIG.search.fields.is_empty() && !CONFIG.search.description {
+    return Err(anyhow!(
+      "At least one search field must be enabled to match the search text"
+    ));
+  }
+
+  crate::utils::reload_tag_aliases()?;
+
+  if let Some(fallback) = &CONFIG.metadata.namespace_fallback {
+    let allowed = CONFIG
+      .metadata
+      .allowed_namespaces
+      .as_ref()
+      .is_some_and(|allowed| allowed.contains(fallback));
+
+    if !allowed {
+      return Err(anyhow!(
+        "The namespace fallback '{fallback}' must be one of the allowed namespaces"
+      ));
+    }
+  }
+
+  // Namespaces end up in the search SQL, so only plain names are accepted
+  let valid = |value: &str| {
+    !value.is_empty()
+      && value
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+  };
+
+  for (keyword, namespace) in &CONFIG.search.tag_keywords {
+    if !valid(keyword) || !valid(namespace) {
+      return Err(anyhow!(
+        "Invalid search tag keyword '{keyword}' for namespace '{namespace}'. Only letters, numbers, '_' and '-' are allowed"
+      ));
+    }
+  }
+
+  Ok(())
+}
+
 pub static REGEX: Lazy<RegexCollection> = Lazy::new(|| {
   let hentag = Regex::new(r#"("coverImageUrl"|"maleTags"|"femaleTags")"#).unwrap();
   let gallery_dl = Regex::new(r#"("artist:.*"|"group:.*"|"male:.*"|"female:.*")"#).unwrap();
@@ -53,18 +145,21 @@ pub struct Config {
   pub thumbnails: Thumbnails,
   #[serde_inline_default(Metadata::default())]
   pub metadata: Metadata,
+  #[serde_inline_default(Search::default())]
+  pub search: Search,
 }
 
 impl Display for Config {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(
       f,
-      "{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
-      self.database, self.server, self.directories, self.thumbnails, self.metadata
+      "{:?}\n{:?}\n{:?}\n{:?}\n{:?}\n{:?}",
+      self.database, self.server, self.directories, self.thumbnails, self.metadata, self.search
     )
   }
 }
 
+#[serde_inline_default]
 #[derive(Deserialize, Debug)]
 pub struct Database {
   pub host: String,
@@ -72,15 +167,34 @@ pub struct Database {
   pub name: String,
   pub user: String,
   pub pass: String,
+  #[serde_inline_default(true)]
+  pub run_migrations: bool,
+  #[serde_inline_default(100)]
+  pub batch_size: usize,
 }
 
 #[serde_inline_default(Server::default())]
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize)]
 pub struct Server {
   #[serde_inline_default("0.0.0.0".to_string())]
   pub host: String,
   #[serde_inline_default(3000)]
   pub port: u16,
+  #[serde(default)]
+  pub admin_token: Option<String>,
+}
+
+impl Debug for Server {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Server")
+      .field("host", &self.host)
+      .field("port", &self.port)
+      .field(
+        "admin_token",
+        &self.admin_token.as_ref().map(|_| "<redacted>"),
+      )
+      .finish()
+  }
 }
 
 impl Default for Server {
@@ -88,6 +202,7 @@ impl Default for Server {
     Self {
       host: "0.0.0.0".to_string(),
       port: 3000,
+      admin_token: None,
     }
   }
 }
@@ -97,8 +212,10 @@ impl Default for Server {
 pub struct Directories {
   #[serde_inline_default("./data".into())]
   pub data: PathBuf,
-  #[serde(skip)]
+  #[serde(default)]
   pub links: PathBuf,
+  #[serde_inline_default(true)]
+  pub create_links: bool,
   #[serde(skip)]
   pub thumbs: PathBuf,
   #[serde(skip)]
@@ -111,14 +228,16 @@ pub struct Directories {
 
 impl Directories {
   fn set_relative(&mut self) {
-    self.links = self.data.join("links");
+    if self.links.as_os_str().is_empty() {
+      self.links = self.data.join("links");
+    }
+
     self.thumbs = self.data.join("thumbs");
     self.torrents = self.data.join("torrents");
   }
 
   fn create_dirs(&self) {
     fs::create_dir_all(&self.data).expect("Failed to create data directory");
-    fs::create_dir_all(&self.links).expect("Failed to create symbolic links directory");
     fs::create_dir_all(&self.thumbs).expect("Failed to create thumbnail directory");
     fs::create_dir_all(&self.torrents).expect("Failed to create torrents directory");
     fs::create_dir_all(&self.log).expect("Failed to create logs directory");
@@ -132,6 +251,7 @@ impl Default for Directories {
     Self {
       data: "./data".into(),
       links: "./data/links".into(),
+      create_links: true,
       thumbs: "./data/thumbs".into(),
       torrents: "./data/torrents".into(),
       content: "./content".into(),
@@ -174,16 +294,118 @@ impl Default for Thumbnails {
 }
 
 #[serde_inline_default]
-#[derive(Deserialize, Clone, Copy, Debug)]
+#[derive(Deserialize, Clone, Debug)]
 pub struct Metadata {
   #[serde_inline_default(true)]
   pub parse_filename_title: bool,
+  #[serde(default)]
+  pub default_rating: Option<db::Rating>,
+  #[serde(default)]
+  pub slug_strategy: SlugStrategy,
+  #[serde(default)]
+  pub tag_aliases: Option<PathBuf>,
+  #[serde(default)]
+  pub allowed_namespaces: Option<Vec<String>>,
+  #[serde(default)]
+  pub namespace_fallback: Option<String>,
 }
 
 impl Default for Metadata {
   fn default() -> Self {
     Self {
       parse_filename_title: true,
+      default_rating: None,
+      slug_strategy: Default::default(),
+      tag_aliases: None,
+      allowed_namespaces: None,
+      namespace_fallback: None,
+    }
+  }
+}
+
+#[serde_inline_default]
+#[derive(Deserialize, Debug)]
+pub struct Search {
+  #[serde_inline_default(Sorting::CreatedAt)]
+  pub default_sort: Sorting,
+  #[serde(default)]
+  pub max_scan: Option<i64>,
+  #[serde_inline_default(20)]
+  pub facet_limit: i64,
+  #[serde_inline_default("[]".to_string())]
+  pub strip_chars: String,
+  #[serde_inline_default(true)]
+  pub description: bool,
+  #[serde(default)]
+  pub tag_terms: db::TagTerms,
+  #[serde_inline_default(default_tag_keywords())]
+  #[serde(deserialize_with = "merge_tag_keywords")]
+  pub tag_keywords: HashMap<String, String>,
+  #[serde_inline_default(db::SearchField::defaults())]
+  pub fields: Vec<db::SearchField>,
+  #[serde(default)]
+  pub excluded_namespaces: Vec<String>,
+}
+
+fn default_tag_keywords() -> HashMap<String, String> {
+  [
+    ("male", "male"),
+    ("female", "female"),
+    ("misc", "misc"),
+    ("other", "misc"),
+  ]
+  .into_iter()
+  .map(|(keyword, namespace)| (keyword.to_string(), namespace.to_string()))
+  .collect()
+}
+
+/// Adds the configured keywords to the default ones, replacing those with the same keyword.
+fn merge_tag_keywords<'de, D>(deserializer: D) -> Result<HashMap<String, String>, D::Error>
+where
+  D: Deserializer<'de>,
+{
+  let mut keywords = default_tag_keywords();
+  keywords.extend(HashMap::<String, String>::deserialize(deserializer)?);
+
+  Ok(keywords)
+}
+
+impl Default for Search {
+  fn default() -> Self {
+    Self {
+      default_sort: Sorting::CreatedAt,
+      max_scan: None,
+      facet_limit: 20,
+      strip_chars: "[]".to_string(),
+      description: true,
+      tag_terms: Default::default(),
+      tag_keywords: default_tag_keywords(),
+      fields: db::SearchField::defaults(),
+      excluded_namespaces: vec![],
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn tag_keywords_are_merged_with_the_defaults() {
+    let search: Search = toml::from_str(
+      r#"[tag_keywords]
+other = "other"
+meta = "meta""#,
+    )
+    .unwrap();
+
+    assert_eq!(search.tag_keywords["other"], "other");
+    assert_eq!(search.tag_keywords["meta"], "meta");
+    assert_eq!(search.tag_keywords["male"], "male");
+    assert_eq!(search.tag_keywords["female"], "female");
+    assert_eq!(search.tag_keywords["misc"], "misc");
+
+    let search: Search = toml::from_str("").unwrap();
+    assert_eq!(search.tag_keywords, default_tag_keywords());
+  }
+}
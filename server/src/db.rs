@@ -1,30 +1,37 @@
 use crate::api;
-use crate::config::CONFIG;
-use crate::utils::tag_alias;
+use crate::config::{Metadata, CONFIG};
+use crate::utils::{normalize_namespace, slugify, tag_alias};
 use crate::{
   api::{
-    models::{ArchiveListItem, ImageDimensions},
-    routes::SearchQuery,
+    models::{ArchiveDiff, ArchiveListItem, FieldDiff, ImageDimensions, SearchDebug, SetDiff},
+    routes::{MatchMode, SearchProjection, SearchQuery},
   },
   utils,
 };
 use anyhow::anyhow;
 use chrono::NaiveDateTime;
 use funty::Fundamental;
-use indicatif::MultiProgress;
+use futures_util::stream::{Stream, TryStreamExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use itertools::Itertools;
-use slug::slugify;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
 use sqlx::Transaction;
 use sqlx::{
-  postgres::{PgConnectOptions, PgSslMode},
+  postgres::{PgConnectOptions, PgRow, PgSslMode},
   types::Json,
   PgPool, Postgres, QueryBuilder, Row,
 };
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
 use std::ops::Mul;
-use tracing::warn;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+use tracing::{debug, warn};
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum TagType {
   Artist,
   Circle,
@@ -73,6 +80,83 @@ impl TagType {
   }
 }
 
+/// The search keyword of the type.
+impl Display for TagType {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      TagType::Artist => write!(f, "artist"),
+      TagType::Circle => write!(f, "circle"),
+      TagType::Magazine => write!(f, "magazine"),
+      TagType::Event => write!(f, "event"),
+      TagType::Publisher => write!(f, "publisher"),
+      TagType::Parody => write!(f, "parody"),
+      TagType::Tag => write!(f, "tag"),
+    }
+  }
+}
+
+#[derive(Debug, Error)]
+pub enum DbError {
+  #[error("Resource not found")]
+  NotFound,
+  #[error("Conflicting data violates the '{constraint}' constraint")]
+  Conflict {
+    constraint: String,
+    /// The other archive holding the conflicting value, when known
+    archive_id: Option<i64>,
+  },
+  #[error("{0}")]
+  Validation(String),
+  #[error(transparent)]
+  Backend(sqlx::Error),
+}
+
+impl From<sqlx::Error> for DbError {
+  fn from(err: sqlx::Error) -> Self {
+    match err {
+      sqlx::Error::RowNotFound => Self::NotFound,
+      sqlx::Error::Database(ref db_err) if db_err.code().as_deref() == Some("23505") => {
+        Self::Conflict {
+          constraint: db_err.constraint().unwrap_or_default().to_string(),
+          archive_id: None,
+        }
+      }
+      err => Self::Backend(err),
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+  Safe,
+  Questionable,
+  Explicit,
+}
+
+impl Display for Rating {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Rating::Safe => write!(f, "safe"),
+      Rating::Questionable => write!(f, "questionable"),
+      Rating::Explicit => write!(f, "explicit"),
+    }
+  }
+}
+
+impl FromStr for Rating {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.trim().to_lowercase().as_str() {
+      "safe" => Ok(Self::Safe),
+      "questionable" => Ok(Self::Questionable),
+      "explicit" => Ok(Self::Explicit),
+      _ => Err(anyhow!("Invalid rating value '{s}'")),
+    }
+  }
+}
+
 #[derive(Default)]
 pub struct Archive {
   pub id: i64,
@@ -84,6 +168,7 @@ pub struct Archive {
   pub size: i64,
   pub cover: Option<ImageDimensions>,
   pub thumbnail: i16,
+  pub rating: Option<String>,
   pub images: Vec<api::models::Image>,
   pub created_at: NaiveDateTime,
   pub released_at: NaiveDateTime,
@@ -102,6 +187,7 @@ pub struct ArchiveImage {
   pub page_number: i16,
   pub width: Option<i16>,
   pub height: Option<i16>,
+  pub is_spread: Option<bool>,
 }
 
 #[derive(sqlx::FromRow, Clone, Debug)]
@@ -123,6 +209,51 @@ pub struct ArchiveSource {
   pub url: Option<String>,
 }
 
+#[derive(sqlx::FromRow)]
+struct Neighbors {
+  prev_id: Option<i64>,
+  prev_slug: Option<String>,
+  next_id: Option<i64>,
+  next_slug: Option<String>,
+}
+
+struct UpsertTarget {
+  id: i64,
+  slug: String,
+  path: String,
+  hash: String,
+}
+
+/// What `upsert_archive` would do with the given data.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UpsertOutcome {
+  Insert,
+  Update(i64),
+  ReplaceCopy { old_id: i64 },
+}
+
+#[derive(sqlx::FromRow, Deserialize, Debug)]
+pub struct TaxonomyId {
+  pub id: i64,
+  pub slug: String,
+  pub name: String,
+}
+
+/// A taxonomy and how many search results have it.
+#[derive(Debug)]
+pub struct TaxonomyFacet {
+  pub taxonomy: TaxonomyId,
+  /// Tags are counted per namespace
+  pub namespace: Option<String>,
+  pub count: i64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TaxonomySort {
+  Name,
+  ArchiveCount,
+}
+
 #[derive(sqlx::FromRow)]
 pub struct ArchiveId {
   pub id: i64,
@@ -139,6 +270,9 @@ pub struct ArchiveRelations {
   pub size: i64,
   pub cover: Option<ImageDimensions>,
   pub thumbnail: i16,
+  pub alt_cover: Option<ImageDimensions>,
+  pub alt_thumbnail: Option<i16>,
+  pub rating: Option<String>,
   pub images: Vec<api::models::Image>,
   pub created_at: NaiveDateTime,
   pub released_at: NaiveDateTime,
@@ -164,6 +298,7 @@ impl From<Archive> for ArchiveRelations {
       size,
       cover,
       thumbnail,
+      rating,
       images,
       created_at,
       released_at,
@@ -179,6 +314,9 @@ impl From<Archive> for ArchiveRelations {
       size,
       cover,
       thumbnail,
+      alt_cover: None,
+      alt_thumbnail: None,
+      rating,
       images,
       created_at,
       released_at,
@@ -205,7 +343,9 @@ pub struct UpsertArchiveData {
   pub pages: Option<i16>,
   pub size: Option<i64>,
   pub thumbnail: Option<i16>,
+  pub alt_thumbnail: Option<i16>,
   pub language: Option<String>,
+  pub rating: Option<Rating>,
   pub released_at: Option<NaiveDateTime>,
   pub deleted_at: Option<NaiveDateTime>,
   pub has_metadata: Option<bool>,
@@ -218,6 +358,7 @@ pub struct UpsertArchiveData {
   pub tags: Option<Vec<(String, String)>>,
   pub sources: Option<Vec<ArchiveSource>>,
   pub images: Option<Vec<ArchiveImage>>,
+  pub cover: Option<ImageDimensions>,
 }
 
 #[derive(Debug, Clone)]
@@ -231,6 +372,36 @@ pub struct Relations {
   pub tags: Option<Vec<(String, String)>>,
   pub sources: Option<Vec<ArchiveSource>>,
   pub images: Option<Vec<ArchiveImage>>,
+  pub cover: Option<ImageDimensions>,
+}
+
+#[derive(Default)]
+pub struct ListRelations {
+  pub artists: Vec<api::models::Taxonomy>,
+  pub circles: Vec<api::models::Taxonomy>,
+  pub magazines: Vec<api::models::Taxonomy>,
+  pub events: Vec<api::models::Taxonomy>,
+  pub publishers: Vec<api::models::Taxonomy>,
+  pub parodies: Vec<api::models::Taxonomy>,
+  pub tags: Vec<api::models::Taxonomy>,
+}
+
+pub struct SearchResults {
+  pub archives: Vec<ArchiveListItem>,
+  pub total: i64,
+  /// The total was capped at `max_scan`, so there are more matches than reported
+  pub capped: bool,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct LibraryStats {
+  pub archives: i64,
+  pub pages: i64,
+  pub size: i64,
+  pub artists: i64,
+  pub tags: i64,
+  pub oldest_released_at: Option<NaiveDateTime>,
+  pub newest_released_at: Option<NaiveDateTime>,
 }
 
 pub async fn get_pool() -> anyhow::Result<PgPool> {
@@ -245,11 +416,94 @@ pub async fn get_pool() -> anyhow::Result<PgPool> {
   )
   .await?;
 
-  sqlx::migrate!("./migrations").run(&pool).await?;
+  let migrator = sqlx::migrate!("./migrations");
+
+  if CONFIG.database.run_migrations {
+    migrator.run(&pool).await?;
+  } else {
+    let expected = migrator.iter().map(|migration| migration.version).max();
+    let applied: Option<i64> =
+      sqlx::query_scalar(r#"SELECT MAX(version) FROM _sqlx_migrations WHERE success"#)
+        .fetch_one(&pool)
+        .await
+        .map_err(|err| anyhow!("Couldn't read the applied database migrations: {err}"))?;
+
+    if applied < expected {
+      return Err(anyhow!(
+        "The database schema is behind. Expected migration {}, found {}",
+        expected.unwrap_or_default(),
+        applied
+          .map(|version| version.to_string())
+          .unwrap_or("none".to_string())
+      ));
+    }
+  }
+
+  sync_excluded_namespaces(
+    &CONFIG.search.excluded_namespaces,
+    CONFIG.database.run_migrations,
+    &pool,
+  )
+  .await?;
 
   Ok(pool)
 }
 
+/// Stores the namespaces excluded from the search index for its triggers. Archives indexed before
+/// a change keep their old terms, so a rebuild is suggested. When `write` isn't set, as when the
+/// schema is managed externally, a mismatch is only reported.
+async fn sync_excluded_namespaces(
+  excluded_namespaces: &[String],
+  write: bool,
+  pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+  let namespaces = excluded_namespaces
+    .iter()
+    .map(|namespace| normalize_namespace(namespace))
+    .unique()
+    .sorted()
+    .collect_vec();
+
+  let mut transaction = pool.begin().await?;
+
+  let stored: Vec<String> = sqlx::query_scalar(r#"SELECT namespace FROM fts_excluded_namespaces"#)
+    .fetch_all(&mut *transaction)
+    .await?;
+
+  if stored.into_iter().sorted().collect_vec() == namespaces {
+    return Ok(());
+  }
+
+  if !write {
+    warn!(
+      target: "db::search",
+      "The namespaces excluded from the search index don't match `search.excluded_namespaces`. Update the `fts_excluded_namespaces` table and run `./server rebuild-fts`"
+    );
+
+    return Ok(());
+  }
+
+  sqlx::query(r#"DELETE FROM fts_excluded_namespaces"#)
+    .execute(&mut *transaction)
+    .await?;
+
+  sqlx::query(
+    r#"INSERT INTO fts_excluded_namespaces (namespace) SELECT * FROM UNNEST($1::text[])"#,
+  )
+  .bind(&namespaces)
+  .execute(&mut *transaction)
+  .await?;
+
+  transaction.commit().await?;
+
+  warn!(
+    target: "db::search",
+    "The namespaces excluded from the search index changed. Run `./server rebuild-fts` to apply them to indexed archives"
+  );
+
+  Ok(())
+}
+
 async fn fetch_taxonomy_data(
   pool: &PgPool,
   tag_type: TagType,
@@ -281,6 +535,33 @@ async fn fetch_tag_data(pool: &PgPool, archive_id: i64) -> Result<Vec<Tag>, sqlx
   .await
 }
 
+pub async fn taxonomy_archive_count(
+  tag_type: TagType,
+  id: i64,
+  namespace: Option<&str>,
+  pool: &PgPool,
+) -> Result<i64, sqlx::Error> {
+  let mut qb = QueryBuilder::<Postgres>::new(format!(
+    r#"SELECT COUNT(DISTINCT archives.id) FROM archives
+      INNER JOIN {relation} ON {relation}.archive_id = archives.id
+      WHERE archives.deleted_at IS NULL AND {relation}.{id} = "#,
+    relation = tag_type.relation(),
+    id = tag_type.id()
+  ));
+
+  qb.push_bind(id);
+
+  if let (TagType::Tag, Some(namespace)) = (&tag_type, namespace) {
+    qb.push(format!(
+      " AND {relation}.namespace = ",
+      relation = tag_type.relation()
+    ))
+    .push_bind(normalize_namespace(namespace));
+  }
+
+  qb.build_query_scalar().fetch_one(pool).await
+}
+
 pub async fn fetch_relations(
   archive_id: i64,
   pool: &PgPool,
@@ -318,24 +599,80 @@ pub async fn fetch_relations(
   ))
 }
 
-pub async fn fetch_archive_data(
+/// Fetches the relations of every given archive with one query per relation kind.
+pub async fn fetch_relations_for_ids(
+  ids: &[i64],
+  projection: SearchProjection,
   pool: &PgPool,
-  id: i64,
-) -> Result<Option<ArchiveRelations>, sqlx::Error> {
+) -> Result<HashMap<i64, ListRelations>, sqlx::Error> {
+  let mut relations: HashMap<i64, ListRelations> = HashMap::new();
+
+  if ids.is_empty() {
+    return Ok(relations);
+  }
+
+  // The minimal projection leaves every other taxonomy empty
+  let tag_types = match projection {
+    SearchProjection::Full => vec![
+      TagType::Artist,
+      TagType::Circle,
+      TagType::Magazine,
+      TagType::Event,
+      TagType::Publisher,
+      TagType::Parody,
+      TagType::Tag,
+    ],
+    SearchProjection::Minimal => vec![TagType::Artist],
+  };
+
+  for tag_type in tag_types {
+    let rows: Vec<(i64, Json<Vec<api::models::Taxonomy>>)> = sqlx::query_as(&format!(
+      r#"SELECT r.archive_id, json_agg(json_build_object('slug', {table}.slug, 'name', {table}.name) ORDER BY {table}.name)
+      FROM {table} INNER JOIN {relation} r ON r.{id} = {table}.id
+      WHERE r.archive_id = ANY($1) GROUP BY r.archive_id"#,
+      table = tag_type.table(),
+      relation = tag_type.relation(),
+      id = tag_type.id()
+    ))
+    .bind(ids)
+    .fetch_all(pool)
+    .await?;
+
+    for (archive_id, Json(items)) in rows {
+      let entry = relations.entry(archive_id).or_default();
+
+      match tag_type {
+        TagType::Artist => entry.artists = items,
+        TagType::Circle => entry.circles = items,
+        TagType::Magazine => entry.magazines = items,
+        TagType::Event => entry.events = items,
+        TagType::Publisher => entry.publishers = items,
+        TagType::Parody => entry.parodies = items,
+        TagType::Tag => entry.tags = items,
+      }
+    }
+  }
+
+  Ok(relations)
+}
+
+pub async fn fetch_archive_data(pool: &PgPool, id: i64) -> Result<ArchiveRelations, DbError> {
   let row = sqlx::query!(
-    r#"SELECT id, slug, title, description, hash, pages, size, thumbnail,
+    r#"SELECT id, slug, title, description, hash, pages, size, thumbnail, alt_thumbnail, rating,
     (SELECT json_build_object('width', width, 'height', height) FROM archive_images WHERE archive_id = id AND page_number = archives.thumbnail) cover,
-    (SELECT json_agg(image) FROM (SELECT json_build_object('filename', filename, 'page_number', page_number, 'width', width, 'height', height) AS image FROM archive_images WHERE archive_id = id ORDER BY page_number ASC) AS ordered_images) images,
+    (SELECT json_build_object('width', width, 'height', height) FROM archive_images WHERE archive_id = id AND page_number = archives.alt_thumbnail) alt_cover,
+    (SELECT json_agg(image) FROM (SELECT json_build_object('filename', filename, 'page_number', page_number, 'width', width, 'height', height, 'is_spread', COALESCE(is_spread, width > height)) AS image FROM archive_images WHERE archive_id = id ORDER BY page_number ASC) AS ordered_images) images,
     created_at, released_at FROM archives WHERE id = $1"#,
     id
   ).fetch_optional(pool).await?;
 
   if let Some(row) = row {
-    let cover = row
-      .cover
-      .map(|cover: serde_json::Value| serde_json::from_value(cover).ok())
-      .unwrap_or_default()
-      .filter(|cover: &ImageDimensions| cover.width.is_some() || cover.height.is_some());
+    let cover = |cover: Option<serde_json::Value>| {
+      cover
+        .map(|cover: serde_json::Value| serde_json::from_value(cover).ok())
+        .unwrap_or_default()
+        .filter(|cover: &ImageDimensions| cover.width.is_some() || cover.height.is_some())
+    };
 
     let archive = Archive {
       id: row.id,
@@ -346,7 +683,8 @@ pub async fn fetch_archive_data(
       pages: row.pages.unwrap_or_default(),
       size: row.size,
       thumbnail: row.thumbnail,
-      cover,
+      rating: row.rating,
+      cover: cover(row.cover),
       images: row
         .images
         .and_then(|images| serde_json::from_value(images).ok())
@@ -356,6 +694,8 @@ pub async fn fetch_archive_data(
     };
 
     let mut relations: ArchiveRelations = archive.into();
+    relations.alt_thumbnail = row.alt_thumbnail;
+    relations.alt_cover = cover(row.alt_cover);
 
     let (artists, circles, magazines, events, publishers, parodies, tags, sources) =
       fetch_relations(relations.id, pool).await?;
@@ -368,127 +708,386 @@ pub async fn fetch_archive_data(
     relations.tags = tags;
     relations.sources = sources;
 
-    Ok(Some(relations))
+    Ok(relations)
   } else {
-    Ok(None)
+    Err(DbError::NotFound)
   }
 }
 
-fn parse_query(query: &str) -> String {
-  if query.is_empty() {
-    return "".to_string();
+/// Turns a search term into a tsquery operand. Terms are matched as prefixes unless they end
+/// with `$`, and a leading `-` negates them.
+fn parse_query_term(term: &str) -> Option<(String, bool)> {
+  let term = term.split(':').last().unwrap();
+  let (term, negate) = match term.strip_prefix('-') {
+    Some(term) => (term, true),
+    None => (term, false),
+  };
+  let (term, prefix) = match term.strip_suffix('$') {
+    Some(term) => (term, ""),
+    None => (term, ":*"),
+  };
+
+  if term.is_empty() {
+    return None;
   }
 
-  let parsed_query = query
-    .replace('&', " ")
-    .split(' ')
-    .map(|s| s.split(':').last().unwrap())
-    .map(|s| {
-      if s.ends_with('$') {
-        s.trim_end_matches('$').to_string()
-      } else {
-        format!("{s}:*").to_string()
+  let operand = if negate {
+    format!("!{term}{prefix}")
+  } else {
+    format!("{term}{prefix}")
+  };
+
+  Some((operand, negate))
+}
+
+/// Turns the free-text search into a `to_tsquery` expression.
+///
+/// Words are joined by `&` or `|` depending on the match mode. An explicit `|` or `&`, either
+/// inside a word (`a|b`) or between words (`a | b`), takes precedence over the mode. Negated
+/// words are always required not to match.
+fn parse_query(query: &str, mode: MatchMode) -> String {
+  let joiner = match mode {
+    MatchMode::All => "&",
+    MatchMode::Any => "|",
+  };
+
+  let mut expression = String::new();
+  let mut negated = vec![];
+  let mut operator = None;
+
+  for word in query.split_whitespace() {
+    if word == "|" || word == "&" {
+      operator = Some(word);
+      continue;
+    }
+
+    let mut group = String::new();
+    let mut operands = 0;
+    let mut is_negated = false;
+    let mut inner_operator = "&";
+
+    for term in word.split_inclusive(['|', '&']) {
+      let (term, next) = match term.strip_suffix(['|', '&']) {
+        Some(stripped) => (stripped, &term[stripped.len()..]),
+        None => (term, ""),
+      };
+
+      if let Some((operand, negate)) = parse_query_term(term) {
+        if operands > 0 {
+          group.push_str(inner_operator);
+        }
+
+        group.push_str(&operand);
+        operands += 1;
+        is_negated = negate;
       }
-    })
-    .map(|s| {
-      if s.starts_with('-') {
-        s.replacen('-', "!", 1)
-      } else {
-        s
+
+      if !next.is_empty() {
+        inner_operator = next;
       }
-    })
-    .collect::<Vec<_>>()
-    .join("&");
-  let mut parsed_query = parsed_query
-    .split('|')
-    .map(|s| s.to_string())
-    .collect::<Vec<String>>();
+    }
 
-  if parsed_query.len() > 1 {
-    parsed_query = parsed_query
-      .iter()
-      .enumerate()
-      .map(|(i, s)| {
-        if i == 0 {
-          if let Some(position) = s
-            .chars()
-            .collect::<Vec<_>>()
-            .iter()
-            .rposition(|s| *s == '&' || *s == '|')
-          {
-            let mut x = s.to_string();
-            x.insert(position + 1, '(');
-            x
-          } else {
-            format!("({s}")
-          }
-        } else if i == parsed_query.len() - 1 {
-          let mut s = s.to_string();
+    if operands == 0 {
+      continue;
+    }
 
-          if let Some(position) = s.char_indices().find(|&(_, c)| c == '&' || c == '|') {
-            s.insert(position.0, ')');
-          } else {
-            s = format!("{s})");
-          }
+    if operands == 1 && is_negated {
+      negated.push(group);
+      continue;
+    }
 
-          s
-        } else {
-          let mut s = s.to_string();
+    if !expression.is_empty() {
+      expression.push_str(operator.unwrap_or(joiner));
+    }
 
-          if let Some(position) = s.char_indices().find(|&(_, c)| c == '&' || c == '|') {
-            s.insert(position.0, ')');
-          }
+    operator = None;
 
-          if let Some(position) = s
-            .chars()
-            .collect::<Vec<_>>()
-            .iter()
-            .rposition(|s| *s == '&')
-          {
-            s.insert(position + 1, '(');
-          }
+    if operands > 1 {
+      expression.push_str(&format!("({group})"));
+    } else {
+      expression.push_str(&group);
+    }
+  }
 
-          s
-        }
-      })
-      .collect::<Vec<_>>();
+  let negated = negated.join("&");
+
+  match (expression.is_empty(), negated.is_empty()) {
+    (true, _) => negated,
+    (false, true) => expression,
+    (false, false) => format!("({expression})&{negated}"),
   }
+}
+
+#[derive(Clone, Copy)]
+enum SearchFilter {
+  Taxonomy(TagType),
+  /// Matches either an artist or a circle
+  Creator,
+  Tag(&'static str),
+  Column(&'static str),
+  Language,
+  /// Page count, like `pages:20`, `pages:10-20` or `pages:odd`
+  Pages,
+  /// Matches archives without any value for the filter given as value, like `no:female`.
+  /// `no:source` matches archives without sources.
+  Missing,
+  /// Opposite of `Missing`, like `has:source`
+  Present,
+}
+
+/// Search keywords, including their aliases, and the filter each one applies.
+///
+/// The tag namespace keywords come from `search.tag_keywords`, after the built-in ones so they
+/// can't shadow them.
+static SEARCH_KEYWORDS: Lazy<Vec<(&'static str, SearchFilter)>> = Lazy::new(|| {
+  let mut keywords = vec![
+    ("artist", SearchFilter::Taxonomy(TagType::Artist)),
+    ("a", SearchFilter::Taxonomy(TagType::Artist)),
+    ("circle", SearchFilter::Taxonomy(TagType::Circle)),
+    ("c", SearchFilter::Taxonomy(TagType::Circle)),
+    ("magazine", SearchFilter::Taxonomy(TagType::Magazine)),
+    ("m", SearchFilter::Taxonomy(TagType::Magazine)),
+    ("event", SearchFilter::Taxonomy(TagType::Event)),
+    ("publisher", SearchFilter::Taxonomy(TagType::Publisher)),
+    ("parody", SearchFilter::Taxonomy(TagType::Parody)),
+    ("p", SearchFilter::Taxonomy(TagType::Parody)),
+    ("creator", SearchFilter::Creator),
+    ("tag", SearchFilter::Tag("%%")),
+    ("title", SearchFilter::Column("archives.title")),
+    ("rating", SearchFilter::Column("archives.rating")),
+    ("language", SearchFilter::Language),
+    ("lang", SearchFilter::Language),
+    ("pages", SearchFilter::Pages),
+    ("no", SearchFilter::Missing),
+    ("has", SearchFilter::Present),
+  ];
+
+  keywords.extend(
+    CONFIG
+      .search
+      .tag_keywords
+      .iter()
+      .sorted()
+      .map(|(keyword, namespace)| (keyword.as_str(), SearchFilter::Tag(namespace.as_str()))),
+  );
+
+  keywords
+});
 
-  let parsed_query = parsed_query
+/// Matches `keyword:value` filters. Group 1 is the whole filter, 2 the keyword and 3 the value.
+static KEYWORD_REGEX: Lazy<Regex> = Lazy::new(|| {
+  let keywords = SEARCH_KEYWORDS
     .iter()
-    .enumerate()
-    .map(|(i, s)| {
-      if i < parsed_query.len() - 1 {
-        if s.ends_with('$') {
-          s.trim_end_matches('$').to_string()
-        } else {
-          format!("{}:*", s)
-        }
-      } else {
-        s.to_string()
-      }
-    })
-    .collect::<Vec<_>>()
+    .map(|(keyword, _)| regex::escape(keyword))
     .join("|");
 
-  parsed_query
+  Regex::new(&format!(
+    r#"(?i)(?:^|[^\w])(-?({keywords}):(".*?"|'.*?'|[^\s{{}}]+))"#
+  ))
+  .unwrap()
+});
+
+fn search_filter(keyword: &str) -> Option<SearchFilter> {
+  let keyword = keyword.to_lowercase();
+
+  SEARCH_KEYWORDS
+    .iter()
+    .find(|(k, _)| *k == keyword)
+    .map(|(_, filter)| *filter)
+}
+
+/// Matches any of the `|` separated languages, case-insensitively. `none` matches archives
+/// without a language, and a negated filter also matches them unless `none` is listed.
+fn push_language_sql(qb: &mut QueryBuilder<Postgres>, value: &str, negate: bool) {
+  let languages = value
+    .split('|')
+    .map(|language| language.trim().to_lowercase())
+    .filter(|language| !language.is_empty())
+    .collect_vec();
+  let include_none = languages.iter().any(|language| language == "none");
+  let languages = languages
+    .into_iter()
+    .filter(|language| language != "none")
+    .collect_vec();
+
+  let condition = if negate { "NOT COALESCE" } else { "COALESCE" };
+
+  qb.push(format!("({condition}(LOWER(archives.language) = ANY("))
+    .push_bind(languages)
+    .push(") OR (")
+    .push_bind(include_none)
+    .push(" AND archives.language IS NULL), FALSE)");
+}
+
+enum PagesFilter {
+  Exact(i16),
+  Range(Option<i16>, Option<i16>),
+  Parity(i16),
+}
+
+/// Matches any of the `|` separated page counts. Each one is an exact count (`20`), a range with
+/// optional bounds (`10-20`, `10-`, `-20`) or `odd`/`even`. Invalid ones are ignored.
+///
+/// Like the other filters, leaves one parenthesis open to be closed by `add_tag_matches`.
+fn push_pages_sql(qb: &mut QueryBuilder<Postgres>, value: &str, negate: bool) {
+  // An empty bound is open, anything else has to be a number
+  let bound = |bound: &str| match bound.trim() {
+    "" => Some(None),
+    bound => bound.parse::<i16>().ok().map(Some),
+  };
+
+  let pages = value
+    .split('|')
+    .map(|pages| pages.trim().to_lowercase())
+    .filter_map(|pages| match pages.as_str() {
+      "odd" => Some(PagesFilter::Parity(1)),
+      "even" => Some(PagesFilter::Parity(0)),
+      _ => match pages.split_once('-') {
+        Some((min, max)) => match (bound(min)?, bound(max)?) {
+          (None, None) => None,
+          (min, max) => Some(PagesFilter::Range(min, max)),
+        },
+        None => pages.parse::<i16>().ok().map(PagesFilter::Exact),
+      },
+    })
+    .collect_vec();
+
+  if pages.is_empty() {
+    qb.push("(TRUE");
+    return;
+  }
+
+  let condition = if negate { "NOT COALESCE" } else { "COALESCE" };
+
+  qb.push(format!("({condition}(("));
+
+  for (i, pages) in pages.into_iter().enumerate() {
+    if i > 0 {
+      qb.push(" OR ");
+    }
+
+    match pages {
+      PagesFilter::Exact(pages) => {
+        qb.push("archives.pages = ").push_bind(pages);
+      }
+      PagesFilter::Range(min, max) => {
+        qb.push("(archives.pages >= COALESCE(")
+          .push_bind(min)
+          .push(", archives.pages) AND archives.pages <= COALESCE(")
+          .push_bind(max)
+          .push(", archives.pages))");
+      }
+      PagesFilter::Parity(remainder) => {
+        qb.push("archives.pages % 2 = ").push_bind(remainder);
+      }
+    }
+  }
+
+  qb.push("), FALSE)");
+}
+
+/// Turns a search value into an `ILIKE` pattern with `*` as the wildcard. Literal `%` and `_`
+/// are escaped so they don't act as wildcards.
+fn like_pattern(value: &str) -> String {
+  value
+    .replace('\\', "\\\\")
+    .replace('%', "\\%")
+    .replace('_', "\\_")
+    .replace('*', "%")
+}
+
+/// Matches a column against the whole value as a single `ILIKE` pattern.
+///
+/// Like the other filters, leaves one parenthesis open to be closed by `add_tag_matches`.
+fn push_column_sql(qb: &mut QueryBuilder<Postgres>, column: &str, value: &str, negate: bool) {
+  let predicate = if negate {
+    format!("{column} IS NULL OR {column} NOT ILIKE ")
+  } else {
+    format!("{column} ILIKE ")
+  };
+
+  qb.push(format!("({predicate}"))
+    .push_bind(like_pattern(value));
+}
+
+/// Like the other filters, leaves one parenthesis open to be closed by `add_tag_matches`.
+fn push_missing_sql(qb: &mut QueryBuilder<Postgres>, value: &str, negate: bool) {
+  let not_exists = |tag_type: TagType| {
+    let relation = tag_type.relation();
+
+    format!("NOT EXISTS (SELECT 1 FROM {relation} WHERE {relation}.archive_id = archives.id")
+  };
+
+  let condition = if negate { "NOT " } else { "" };
+
+  let sql = match search_filter(value) {
+    _ if matches!(value.to_lowercase().as_str(), "source" | "sources") => {
+      "NOT EXISTS (SELECT 1 FROM archive_sources WHERE archive_sources.archive_id = archives.id)"
+        .to_string()
+    }
+    Some(SearchFilter::Taxonomy(tag_type)) => format!("{})", not_exists(tag_type)),
+    Some(SearchFilter::Creator) => format!(
+      "{}) AND {})",
+      not_exists(TagType::Artist),
+      not_exists(TagType::Circle)
+    ),
+    Some(SearchFilter::Tag(namespace)) => {
+      qb.push(format!(
+        "({condition}({} AND archive_tags.namespace ILIKE ",
+        not_exists(TagType::Tag)
+      ))
+      .push_bind(namespace)
+      .push("))");
+      return;
+    }
+    Some(SearchFilter::Column(column)) => format!("{column} IS NULL"),
+    Some(SearchFilter::Language) => "archives.language IS NULL".to_string(),
+    Some(SearchFilter::Pages) => "archives.pages IS NULL".to_string(),
+    Some(SearchFilter::Missing | SearchFilter::Present) | None => {
+      qb.push("(TRUE");
+      return;
+    }
+  };
+
+  qb.push(format!("({condition}({sql})"));
 }
 
 fn add_tag_matches(qb: &mut QueryBuilder<Postgres>, value: &str) {
-  let re = regex::Regex::new(
-    r#"(?i)-?(artist|circle|magazine|event|publisher|parody|tag|male|female|misc|other|title|pages):(".*?"|'.*?'|[^\s]+)"#,
-  )
-  .unwrap();
+  let captures = KEYWORD_REGEX.captures_iter(value).collect_vec();
 
-  let captures = re.captures_iter(value).collect_vec();
+  // Filters wrapped in `{ }` are OR'd together instead of AND'd
+  let groups = Regex::new(r#"\{[^{}]*\}"#)
+    .unwrap()
+    .find_iter(value)
+    .map(|m| (m.start(), m.end()))
+    .collect_vec();
 
-  for capture in captures.into_iter() {
-    qb.push(" AND (");
+  let get_group = |capture: &regex::Captures| {
+    let capture = capture.get(1).unwrap();
+    groups
+      .iter()
+      .position(|(start, end)| capture.start() > *start && capture.end() < *end)
+  };
 
-    let negate = capture.get(0).unwrap().as_str().starts_with('-');
-    let condition = if negate { "NOT EXISTS" } else { "EXISTS" };
+  for (k, capture) in captures.iter().enumerate() {
+    let Some(filter) = search_filter(capture.get(2).unwrap().as_str()) else {
+      continue;
+    };
 
-    let tag_type = capture.get(1).unwrap().as_str().to_lowercase();
+    let group = get_group(capture);
+    let prev_group = k.checked_sub(1).and_then(|k| get_group(&captures[k]));
+    let next_group = captures.get(k + 1).and_then(get_group);
+
+    if group.is_none() {
+      qb.push(" AND (");
+    } else if group != prev_group {
+      qb.push(" AND ((");
+    } else {
+      qb.push(" OR (");
+    }
+
+    let negate = capture.get(1).unwrap().as_str().starts_with('-');
+    let condition = if negate { "NOT EXISTS" } else { "EXISTS" };
 
     let get_sql = |tag_type: &TagType, column: &str| {
       format!(
@@ -512,183 +1111,668 @@ fn add_tag_matches(qb: &mut QueryBuilder<Postgres>, value: &str) {
       |qb: &mut QueryBuilder<Postgres>, tag_type: TagType, value: String, namespace: String| {
         qb.push(get_sql(&tag_type, "name"))
           .push_bind(value.clone())
-          .push(format!(" AND namespace ILIKE '{namespace}'"))
+          .push(" AND namespace ILIKE ")
+          .push_bind(namespace.clone())
           .push(format!("\n        ) OR\n        {condition} (\n          "))
           .push(get_sql(&tag_type, "slug"))
           .push_bind(value)
-          .push(format!(" AND namespace ILIKE '{namespace}'"))
+          .push(" AND namespace ILIKE ")
+          .push_bind(namespace)
           .push("\n        )\n      )\n".to_string());
       };
 
     let value = capture
-      .get(2)
+      .get(3)
       .unwrap()
       .as_str()
       .trim_matches('\"')
-      .trim_matches('\'')
-      .replace('*', "%")
-      .replace(['(', ')'], "");
+      .trim_matches('\'');
+
+    let column_value = value;
+    let value = value.replace('*', "%").replace(['(', ')'], "");
+
+    // Titles can legitimately contain parentheses, `|` and `&`, so columns are matched against
+    // the whole value
+    if let SearchFilter::Column(column) = filter {
+      push_column_sql(qb, column, column_value, negate);
+    } else if let SearchFilter::Language = filter {
+      push_language_sql(qb, &value, negate);
+    } else if let SearchFilter::Pages = filter {
+      push_pages_sql(qb, &value, negate);
+    } else if let SearchFilter::Missing = filter {
+      push_missing_sql(qb, &value, negate);
+    } else if let SearchFilter::Present = filter {
+      push_missing_sql(qb, &value, !negate);
+    } else {
+      let or_splits = value.split('|').collect_vec();
+
+      for (i, or_split) in or_splits.iter().enumerate() {
+        qb.push("  (\n");
+        let and_splits = or_split.split('&').collect_vec();
 
-    let or_splits = value.split('|').collect_vec();
+        if i == 0 {
+          qb.push("    (\n");
+        }
 
-    for (i, or_split) in or_splits.iter().enumerate() {
-      qb.push("  (\n");
-      let and_splits = or_split.split('&').collect_vec();
+        for (j, and_split) in and_splits.iter().enumerate() {
+          let and_split = and_split.to_string();
 
-      if i == 0 {
-        qb.push("    (\n");
-      }
+          if let SearchFilter::Creator = filter {
+            qb.push("(");
+          }
 
-      for (j, and_split) in and_splits.iter().enumerate() {
-        qb.push(format!("      (\n        {condition} (\n          "));
-
-        let and_split = and_split.to_string();
-
-        match tag_type.as_str() {
-          "artist" => push_taxonomy_sql(qb, TagType::Artist, and_split),
-          "circle" => push_taxonomy_sql(qb, TagType::Circle, and_split),
-          "magazine" => push_taxonomy_sql(qb, TagType::Magazine, and_split),
-          "publisher" => push_taxonomy_sql(qb, TagType::Publisher, and_split),
-          "parody" => push_taxonomy_sql(qb, TagType::Parody, and_split),
-          "tag" => push_tag_sql_sql(qb, TagType::Tag, and_split, "%%".to_string()),
-          "male" => push_tag_sql_sql(qb, TagType::Tag, and_split, "male".to_string()),
-          "female" => push_tag_sql_sql(qb, TagType::Tag, and_split, "female".to_string()),
-          "misc" | "other" => push_tag_sql_sql(qb, TagType::Tag, and_split, "misc".to_string()),
-          _ => {}
-        }
+          qb.push(format!("      (\n        {condition} (\n          "));
+
+          match filter {
+            SearchFilter::Taxonomy(tag_type) => push_taxonomy_sql(qb, tag_type, and_split),
+            SearchFilter::Creator => {
+              push_taxonomy_sql(qb, TagType::Artist, and_split.clone());
+              // A negated filter excludes the archive if either of them matches
+              qb.push(if negate { " AND " } else { " OR " })
+                .push(format!("      (\n        {condition} (\n          "));
+              push_taxonomy_sql(qb, TagType::Circle, and_split);
+              qb.push(")");
+            }
+            SearchFilter::Tag(namespace) => {
+              push_tag_sql_sql(qb, TagType::Tag, and_split, namespace.to_string())
+            }
+            SearchFilter::Column(_)
+            | SearchFilter::Language
+            | SearchFilter::Pages
+            | SearchFilter::Missing
+            | SearchFilter::Present => {}
+          }
 
-        if j != and_splits.len() - 1 {
-          qb.push(" AND ");
-        } else {
-          qb.push("    )");
+          if j != and_splits.len() - 1 {
+            qb.push(" AND ");
+          } else {
+            qb.push("    )");
+          }
         }
-      }
 
-      if i != or_splits.len() - 1 {
-        qb.push(" OR\n  ");
+        if i != or_splits.len() - 1 {
+          qb.push(" OR\n  ");
+        }
       }
     }
 
     qb.push("))");
+
+    if group.is_some() && group != next_group {
+      qb.push(")");
+    }
   }
 }
 
 fn clean_value(query: &str) -> String {
   let mut value = query.to_owned();
 
-  let re = regex::Regex::new(
-    r#"(?i)-?(artist|circle|magazine|event|publisher|parody|tag|male|female|misc|other|title|pages):(".*?"|'.*?'|[^\s]+)"#,
-  )
-  .unwrap();
-  let captures = re.captures_iter(query).collect_vec();
+  let captures = KEYWORD_REGEX.captures_iter(query).collect_vec();
 
   for capture in captures {
-    let capture = capture.get(0).unwrap();
+    let capture = capture.get(1).unwrap();
     value = value.replace(capture.as_str(), "");
   }
 
   value.trim().replace(':', "").to_string()
 }
 
-pub async fn search(
-  query: &SearchQuery,
-  pool: &PgPool,
-) -> Result<(Vec<ArchiveListItem>, i64), sqlx::Error> {
-  let strip_set: HashSet<char> = vec!['[', ']', '(', ')', '~', '&'].into_iter().collect();
-  let stripped: String = query
-    .value
-    .chars()
-    .filter(|&c| !strip_set.contains(&c))
+/// Takes out double quoted phrases from the free-text search.
+///
+/// Phrases are matched literally against the title instead of going through `to_tsquery`, so
+/// they keep English stopwords and numbers. A leading `-` excludes the phrase instead.
+fn extract_phrases(value: &str) -> (String, Vec<(bool, String)>) {
+  let re = Regex::new(r#"(-?)"([^"]*)""#).unwrap();
+
+  let phrases = re
+    .captures_iter(value)
+    .map(|capture| {
+      (
+        !capture.get(1).unwrap().as_str().is_empty(),
+        capture.get(2).unwrap().as_str().trim().to_string(),
+      )
+    })
+    .filter(|(_, phrase)| !phrase.is_empty())
     .collect();
 
-  let value = utils::trim_whitespace(&stripped);
-  let clean = &utils::trim_whitespace(&clean_value(&value));
-  let parsed = parse_query(clean);
-
-  let mut qb = QueryBuilder::new(
-    r#"SELECT COUNT(*) FROM archives INNER JOIN archive_fts fts ON fts.archive_id = archives.id WHERE deleted_at IS NULL"#,
-  );
+  (re.replace_all(value, " ").to_string(), phrases)
+}
 
+/// Adds the free-text conditions.
+///
+/// A tsquery left without lexemes (ex: only English stopwords) is ignored instead of matching
+/// nothing.
+fn add_text_matches(qb: &mut QueryBuilder<Postgres>, parsed: &str, phrases: &[(bool, String)]) {
   if !parsed.is_empty() {
-    qb.push(
-      r#" AND (title_tsv || artists_tsv || circles_tsv || magazines_tsv || parodies_tsv || tags_tsv) @@ to_tsquery('english', "#,
-    )
-    .push_bind(&parsed)
-    .push(")");
+    qb.push(" AND (numnode(to_tsquery('english', ")
+      .push_bind(parsed.to_string())
+      .push(format!(
+        ")) = 0 OR {} @@ to_tsquery('english', ",
+        search_vector()
+      ))
+      .push_bind(parsed.to_string())
+      .push("))");
   }
 
-  add_tag_matches(&mut qb, &query.value);
+  for (negate, phrase) in phrases {
+    let operator = if *negate { "NOT ILIKE" } else { "ILIKE" };
 
-  let count: i64 = qb.build_query_scalar().fetch_one(pool).await?;
+    qb.push(format!(" AND archives.title {operator} '%' || "))
+      .push_bind(like_pattern(phrase))
+      .push(" || '%'");
+  }
+}
 
-  let mut qb = QueryBuilder::new(r#"SELECT archives.id"#);
+/// How tags are indexed for free-text search.
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagTerms {
+  /// Only the tag names, like `foo`.
+  #[default]
+  Unqualified,
+  /// The tag names prefixed by their namespace, like `female-foo`. The name words still match
+  /// on their own, but a namespaced tag ranks better when its namespace is searched for.
+  Qualified,
+  /// Both of them.
+  Both,
+}
 
-  if !parsed.is_empty() {
-    qb.push(", ts_rank((title_tsv || artists_tsv || circles_tsv || magazines_tsv || parodies_tsv || tags_tsv), to_tsquery('english', ")
-      .push_bind(&parsed)
-      .push(")) rank");
+/// Namespaced terms of the `female:foo` like filters in the search, used to rank archives with the
+/// namespaced tag above those with the same tag in another namespace.
+fn qualified_tag_terms(value: &str) -> Vec<String> {
+  if let TagTerms::Unqualified = CONFIG.search.tag_terms {
+    return vec![];
   }
 
-  qb.push(r#" FROM archives INNER JOIN archive_fts fts ON fts.archive_id = archives.id WHERE deleted_at IS NULL"#);
+  KEYWORD_REGEX
+    .captures_iter(value)
+    .filter(|capture| !capture.get(1).unwrap().as_str().starts_with('-'))
+    .filter_map(|capture| {
+      let Some(SearchFilter::Tag(namespace)) = search_filter(capture.get(2).unwrap().as_str())
+      else {
+        return None;
+      };
 
-  if !parsed.is_empty() {
-    qb.push(
-      r#" AND (title_tsv || artists_tsv || circles_tsv || magazines_tsv || parodies_tsv || tags_tsv) @@ to_tsquery('english', "#,
-    )
-    .push_bind(&parsed)
-    .push(")");
+      let value = capture.get(3).unwrap().as_str();
+
+      if namespace == "%%" || value.contains('*') {
+        return None;
+      }
+
+      let value = value
+        .trim_matches(['"', '\''])
+        .chars()
+        .map(|c| if c.is_whitespace() { '-' } else { c })
+        .filter(|c| c.is_alphanumeric() || *c == '-')
+        .collect::<String>();
+
+      (!value.is_empty()).then(|| format!("{namespace}-{value}"))
+    })
+    .collect()
+}
+
+/// Metadata fields that can be matched by free-text search.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchField {
+  Title,
+  Artists,
+  Circles,
+  Magazines,
+  Events,
+  Publishers,
+  Parodies,
+  Tags,
+}
+
+impl SearchField {
+  /// Fields matched unless configured otherwise.
+  pub fn defaults() -> Vec<SearchField> {
+    vec![
+      SearchField::Title,
+      SearchField::Artists,
+      SearchField::Circles,
+      SearchField::Magazines,
+      SearchField::Events,
+      SearchField::Publishers,
+      SearchField::Parodies,
+      SearchField::Tags,
+    ]
+  }
+}
+
+/// Combined tsvector of the `archive_fts` columns matched by free-text search.
+///
+/// The description is weighted below every other column, so it only decides the ranking between
+/// archives that don't match anywhere else.
+fn search_vector() -> String {
+  let mut columns = vec![];
+
+  for field in &CONFIG.search.fields {
+    match field {
+      SearchField::Title => columns.push("title_tsv"),
+      SearchField::Artists => columns.push("artists_tsv"),
+      SearchField::Circles => columns.push("circles_tsv"),
+      SearchField::Magazines => columns.push("magazines_tsv"),
+      SearchField::Events => columns.push("events_tsv"),
+      SearchField::Publishers => columns.push("publishers_tsv"),
+      SearchField::Parodies => columns.push("parodies_tsv"),
+      SearchField::Tags => match CONFIG.search.tag_terms {
+        TagTerms::Unqualified => columns.push("tags_tsv"),
+        TagTerms::Qualified => columns.push("qualified_tags_tsv"),
+        TagTerms::Both => columns.extend(["tags_tsv", "qualified_tags_tsv"]),
+      },
+    }
+  }
+
+  if CONFIG.search.description {
+    columns.push("description_tsv");
+  }
+
+  format!("({})", columns.join(" || "))
+}
+
+fn add_favorites_join(qb: &mut QueryBuilder<Postgres>, favorited_by: Option<i64>) {
+  if let Some(user_id) = favorited_by {
+    qb.push(" INNER JOIN favorites ON favorites.archive_id = archives.id AND favorites.user_id = ")
+      .push_bind(user_id);
+  }
+}
+
+/// Characters that would break `to_tsquery` or the filter groups, always removed from free text.
+/// Quoted phrases are taken out before, so they keep them.
+const TSQUERY_CHARS: &[char] = &['(', ')', '{', '}', '"', '~'];
+
+/// Turns the free-text part of a search into a `to_tsquery` string and its quoted phrases.
+fn parse_search_text(value: &str, mode: MatchMode) -> (String, Vec<(bool, String)>) {
+  // Filters are removed before stripping so quoted values are never mangled
+  let value = clean_value(value);
+  let (value, phrases) = extract_phrases(&value);
+
+  let strip_set: HashSet<char> = TSQUERY_CHARS
+    .iter()
+    .copied()
+    .chain(CONFIG.search.strip_chars.chars())
+    .collect();
+  let stripped: String = value.chars().filter(|&c| !strip_set.contains(&c)).collect();
+
+  let clean = &utils::trim_whitespace(&stripped);
+
+  (parse_query(clean, mode), phrases)
+}
+
+/// Describes how a search value is interpreted, for debugging unexpected results.
+pub fn explain_search(query: &SearchQuery) -> SearchDebug {
+  let value = &query.value;
+  let (parsed, phrases) = parse_search_text(value, query.match_mode);
+
+  SearchDebug {
+    parsed,
+    filters: KEYWORD_REGEX
+      .captures_iter(value)
+      .map(|capture| capture.get(1).unwrap().as_str().trim().to_string())
+      .collect(),
+    phrases: phrases
+      .into_iter()
+      .map(|(negate, phrase)| {
+        if negate {
+          format!("-\"{phrase}\"")
+        } else {
+          format!("\"{phrase}\"")
+        }
+      })
+      .collect(),
   }
+}
+
+fn add_deleted_filter(qb: &mut QueryBuilder<Postgres>, query: &SearchQuery) {
+  if query.only_deleted {
+    qb.push(" WHERE archives.deleted_at IS NOT NULL");
+  } else if query.include_deleted {
+    qb.push(" WHERE TRUE");
+  } else {
+    qb.push(" WHERE archives.deleted_at IS NULL");
+  }
+}
+
+/// Pushes the `FROM` and `WHERE` clauses shared by every query over the search results, so
+/// they can't drift apart.
+fn push_search_filters(
+  qb: &mut QueryBuilder<Postgres>,
+  query: &SearchQuery,
+  parsed: &str,
+  phrases: &[(bool, String)],
+) {
+  qb.push(r#" FROM archives INNER JOIN archive_fts fts ON fts.archive_id = archives.id"#);
+
+  add_favorites_join(qb, query.favorited_by);
+  add_deleted_filter(qb, query);
+  add_text_matches(qb, parsed, phrases);
+  add_tag_matches(qb, &query.value);
+  add_blacklist(qb, &query.blacklist);
+}
+
+/// Excludes archives with any of the blacklisted `keyword:slug` taxonomies. Only taxonomy and tag
+/// keywords are accepted, anything else is skipped instead of guessing what it refers to.
+fn add_blacklist(qb: &mut QueryBuilder<Postgres>, blacklist: &[String]) {
+  for entry in blacklist {
+    let Some((keyword, slug)) = entry.split_once(':') else {
+      debug!(target: "db::search", "Skipping blacklist entry without a keyword '{entry}'");
+      continue;
+    };
+
+    let (tag_types, namespace) = match search_filter(keyword) {
+      Some(SearchFilter::Taxonomy(tag_type)) => (vec![tag_type], None),
+      Some(SearchFilter::Creator) => (vec![TagType::Artist, TagType::Circle], None),
+      Some(SearchFilter::Tag(namespace)) => (vec![TagType::Tag], Some(namespace)),
+      _ => {
+        debug!(target: "db::search", "Skipping blacklist entry with unknown keyword '{entry}'");
+        continue;
+      }
+    };
+
+    for tag_type in tag_types {
+      let relation = tag_type.relation();
+      let table = tag_type.table();
+
+      qb.push(format!(
+        r#" AND NOT EXISTS (SELECT 1 FROM {relation} INNER JOIN {table} ON {table}.id = {relation}.{id} WHERE {relation}.archive_id = archives.id"#,
+        id = tag_type.id()
+      ));
+
+      if let Some(namespace) = namespace {
+        qb.push(format!(" AND {relation}.namespace ILIKE "))
+          .push_bind(namespace.to_string());
+      }
 
-  add_tag_matches(&mut qb, &query.value);
+      qb.push(format!(" AND {table}.slug = "))
+        .push_bind(slug.trim().to_lowercase())
+        .push(")");
+    }
+  }
+}
 
+fn push_search_grouping(qb: &mut QueryBuilder<Postgres>, query: &SearchQuery) {
   qb.push(" GROUP BY archives.id, fts.archive_id");
 
-  match query.sort {
+  if query.favorited_by.is_some() {
+    qb.push(", favorites.created_at");
+  }
+}
+
+fn push_random_order(qb: &mut QueryBuilder<Postgres>, seed: &str) {
+  qb.push("md5(archives.id::text || ")
+    .push_bind(seed.to_string())
+    .push(")");
+}
+
+/// Pushes how well an archive matches the free-text search.
+fn push_search_rank(qb: &mut QueryBuilder<Postgres>, query: &SearchQuery, parsed: &str) {
+  qb.push(format!(
+    "ts_rank({}, to_tsquery('english', ",
+    search_vector()
+  ))
+  .push_bind(parsed.to_string())
+  .push("))");
+
+  let qualified = qualified_tag_terms(&query.value);
+
+  if !qualified.is_empty() {
+    qb.push(" + ts_rank(fts.qualified_tags_tsv, to_tsquery('english', ")
+      .push_bind(qualified.join(" | "))
+      .push("))");
+  }
+}
+
+/// Pushes the `ORDER BY` clause of the search, shared by every query that has to follow the
+/// same order as the listed results.
+fn push_search_order(qb: &mut QueryBuilder<Postgres>, query: &SearchQuery, parsed: &str) {
+  // Without a text search there's nothing to rank, so the configured sort is used instead
+  let sort = match query.sort {
+    crate::api::routes::Sorting::Relevance if parsed.is_empty() => CONFIG.search.default_sort,
+    sort => sort,
+  };
+
+  match sort {
     crate::api::routes::Sorting::Relevance => {
       if !parsed.is_empty() {
-        qb.push(format!(
-          r#" ORDER BY rank {order}, created_at {order}"#,
-          order = query.order.to_string()
-        ));
+        // The best matches always come first, the ordering only applies to ties
+        qb.push(" ORDER BY ");
+        push_search_rank(qb, query, parsed);
+        qb.push(format!(r#" DESC, archives.created_at {}"#, query.order));
       } else {
-        qb.push(format!(r#" ORDER BY created_at {}"#, query.order));
+        qb.push(format!(r#" ORDER BY archives.created_at {}"#, query.order));
       }
     }
     crate::api::routes::Sorting::ReleasedAt => {
       qb.push(format!(r#" ORDER BY released_at {}"#, query.order));
     }
     crate::api::routes::Sorting::CreatedAt => {
-      qb.push(format!(r#" ORDER BY created_at {}"#, query.order));
+      qb.push(format!(r#" ORDER BY archives.created_at {}"#, query.order));
     }
     crate::api::routes::Sorting::Title => {
       qb.push(format!(r#" ORDER BY archives.title {}"#, query.order));
     }
+    crate::api::routes::Sorting::Artist => {
+      // Archives without artists go last in either direction
+      qb.push(format!(
+        r#" ORDER BY (
+          SELECT MIN(artists.name) FROM archive_artists
+          INNER JOIN artists ON artists.id = archive_artists.artist_id
+          WHERE archive_artists.archive_id = archives.id
+        ) {} NULLS LAST"#,
+        query.order
+      ));
+    }
     crate::api::routes::Sorting::Pages => {
       qb.push(format!(
-        r#" ORDER BY pages {order}, created_at {order}"#,
+        r#" ORDER BY pages {order}, archives.created_at {order}"#,
         order = query.order
       ));
     }
+    crate::api::routes::Sorting::Favorited => {
+      if query.favorited_by.is_some() {
+        qb.push(format!(r#" ORDER BY favorites.created_at {}"#, query.order));
+      } else {
+        qb.push(format!(r#" ORDER BY archives.created_at {}"#, query.order));
+      }
+    }
+    crate::api::routes::Sorting::Random => {
+      // Hashing with the seed gives a shuffled order that stays the same across pages
+      qb.push(" ORDER BY ");
+      push_random_order(qb, query.seed.as_deref().unwrap_or_default());
+    }
+  };
+
+  // Ties would otherwise come back in any order, moving archives between pages
+  qb.push(format!(", archives.id {}", query.order));
+}
+
+pub async fn search(query: &SearchQuery, pool: &PgPool) -> Result<SearchResults, DbError> {
+  let (parsed, phrases) = parse_search_text(&query.value, query.match_mode);
+
+  let mut qb = QueryBuilder::new(r#"SELECT COUNT(*) FROM (SELECT 1"#);
+
+  push_search_filters(&mut qb, query, &parsed, &phrases);
+
+  // Counting stops after `max_scan` matches, one more is fetched to know if there are more
+  if let Some(max_scan) = CONFIG.search.max_scan {
+    qb.push(" LIMIT ").push_bind(max_scan + 1);
+  }
+
+  qb.push(") matches");
+
+  let count: i64 = qb.build_query_scalar().fetch_one(pool).await?;
+  let (total, capped) = match CONFIG.search.max_scan {
+    Some(max_scan) if count > max_scan => (max_scan, true),
+    _ => (count, false),
   };
 
+  let offset = 24.mul(query.page - 1).as_i64();
+
+  // Nothing to rank or hydrate without matches, or for a page past the last one. A capped count
+  // doesn't know where the last page is, so those pages still run the query.
+  if count == 0 || (!capped && offset >= count) {
+    return Ok(SearchResults {
+      archives: vec![],
+      total,
+      capped,
+    });
+  }
+
+  let mut qb = QueryBuilder::new(r#"SELECT archives.id"#);
+
+  push_search_filters(&mut qb, query, &parsed, &phrases);
+  push_search_grouping(&mut qb, query);
+  push_search_order(&mut qb, query, &parsed);
+
   qb.push(" LIMIT ")
     .push_bind(24)
     .push(" OFFSET ")
-    .push_bind(24.mul(query.page - 1).as_i32());
+    .push_bind(offset);
 
   let rows = qb.build().fetch_all(pool).await?;
 
   let ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
 
-  let mut qb = QueryBuilder::new(
-    r#"SELECT id, slug, hash, title,
+  // A page past the end of a capped count, or of results removed since counting, has no ids
+  if ids.is_empty() {
+    return Ok(SearchResults {
+      archives: vec![],
+      total,
+      capped,
+    });
+  }
+
+  let mut relations = fetch_relations_for_ids(&ids, query.projection, pool).await?;
+
+  // Joining the page ids with their position keeps the order of the previous query
+  let mut qb = QueryBuilder::new(format!(r#"SELECT {LIST_ITEM_COLUMNS} FROM UNNEST("#));
+
+  qb.push_bind(&ids)
+    .push("::bigint[]) WITH ORDINALITY AS page (id, ord) INNER JOIN archives USING (id) ORDER BY page.ord");
+
+  let rows = qb.build().fetch_all(pool).await?;
+
+  let archives = rows
+    .iter()
+    .map(|row| archive_list_item(row, &mut relations))
+    .collect();
+
+  Ok(SearchResults {
+    archives,
+    total,
+    capped,
+  })
+}
+
+/// Columns selected for an `ArchiveListItem`, in the order read by `archive_list_item`.
+const LIST_ITEM_COLUMNS: &str = r#"id, slug, hash, title,
     (
       SELECT json_build_object('width', width, 'height', height)
       FROM archive_images WHERE archive_id = id AND page_number = thumbnail
-    ) cover,"#,
-  );
+    ) cover, rating"#;
+
+fn archive_list_item(row: &PgRow, relations: &mut HashMap<i64, ListRelations>) -> ArchiveListItem {
+  let id: i64 = row.get(0);
+  let cover = row
+    .try_get::<Json<_>, _>(4)
+    .map(|r| r.0)
+    .unwrap_or(None)
+    .filter(|cover: &ImageDimensions| cover.width.is_some() || cover.height.is_some());
+  let relations = relations.remove(&id).unwrap_or_default();
+
+  ArchiveListItem {
+    id,
+    slug: row.get(1),
+    hash: row.get(2),
+    title: row.get(3),
+    cover,
+    rating: row.get(5),
+    artists: relations.artists,
+    circles: relations.circles,
+    magazines: relations.magazines,
+    events: relations.events,
+    publishers: relations.publishers,
+    parodies: relations.parodies,
+    tags: relations.tags,
+  }
+}
 
-  for tag_type in [
+/// Latest published archives. A single query, without the counting and pagination of `search`.
+pub async fn recent_archives(
+  limit: i64,
+  pool: &PgPool,
+) -> Result<Vec<ArchiveListItem>, sqlx::Error> {
+  let rows = sqlx::query(&format!(
+    r#"SELECT {LIST_ITEM_COLUMNS} FROM archives WHERE deleted_at IS NULL
+    ORDER BY created_at DESC, id DESC LIMIT $1"#
+  ))
+  .bind(limit)
+  .fetch_all(pool)
+  .await?;
+
+  let ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+  let mut relations = fetch_relations_for_ids(&ids, SearchProjection::Full, pool).await?;
+
+  Ok(
+    rows
+      .iter()
+      .map(|row| archive_list_item(row, &mut relations))
+      .collect(),
+  )
+}
+
+/// Counts the search results by release year. Years without results are left out.
+pub async fn release_year_facets(
+  query: &SearchQuery,
+  pool: &PgPool,
+) -> Result<Vec<(i32, i64)>, sqlx::Error> {
+  let (parsed, phrases) = parse_search_text(&query.value, query.match_mode);
+
+  let mut qb =
+    QueryBuilder::new(r#"SELECT EXTRACT(YEAR FROM archives.released_at)::int AS year, COUNT(*)"#);
+
+  push_search_filters(&mut qb, query, &parsed, &phrases);
+
+  qb.push(" AND archives.released_at IS NOT NULL GROUP BY year ORDER BY year DESC");
+
+  qb.build_query_as().fetch_all(pool).await
+}
+
+/// Counts the published archives by language, most used first. Archives without a language are
+/// counted as `none` when `include_none` is set, which matches them in the `language:` filter.
+/// Languages are grouped case-insensitively, like the filter, and listed by their most used
+/// spelling.
+pub async fn distinct_languages(
+  include_none: bool,
+  pool: &PgPool,
+) -> Result<Vec<(String, i64)>, sqlx::Error> {
+  sqlx::query_as(
+    r#"SELECT COALESCE(MODE() WITHIN GROUP (ORDER BY language), 'none') AS language, COUNT(*) AS count
+    FROM archives
+    WHERE deleted_at IS NULL AND ($1 OR language IS NOT NULL)
+    GROUP BY LOWER(archives.language) ORDER BY count DESC, language ASC"#,
+  )
+  .bind(include_none)
+  .fetch_all(pool)
+  .await
+}
+
+/// Returns the most common taxonomies of each type among the search results.
+pub async fn taxonomy_facets(
+  query: &SearchQuery,
+  pool: &PgPool,
+) -> Result<HashMap<TagType, Vec<TaxonomyFacet>>, sqlx::Error> {
+  let (parsed, phrases) = parse_search_text(&query.value, query.match_mode);
+
+  let mut qb = QueryBuilder::new(r#"WITH matches AS (SELECT archives.id"#);
+
+  push_search_filters(&mut qb, query, &parsed, &phrases);
+
+  qb.push(")");
+
+  let tag_types = [
     TagType::Artist,
     TagType::Circle,
     TagType::Magazine,
@@ -696,94 +1780,140 @@ pub async fn search(
     TagType::Publisher,
     TagType::Parody,
     TagType::Tag,
-  ] {
-    qb.push(format!(
-        r#" COALESCE((SELECT json_agg(json_build_object('slug', {table}.slug, 'name', {table}.name) ORDER BY {table}.name)
-        FROM {table} INNER JOIN {relation} r ON r.{id} = {table}.id
-        WHERE r.archive_id = archives.id), '[]') {table}"#,
-        table = tag_type.table(),
-        relation = tag_type.relation(),
-        id = tag_type.id()
-      ));
+  ];
 
-    if tag_type != TagType::Tag {
-      qb.push(",");
+  for (i, tag_type) in tag_types.iter().enumerate() {
+    if i > 0 {
+      qb.push(" UNION ALL");
     }
+
+    let (namespace, group) = match tag_type {
+      TagType::Tag => ("r.namespace", ", r.namespace"),
+      _ => ("NULL::text", ""),
+    };
+
+    qb.push(format!(
+      r#" (SELECT {i} AS type, {table}.id, {table}.slug, {table}.name, {namespace} AS namespace, COUNT(DISTINCT r.archive_id) AS count
+      FROM {table} INNER JOIN {relation} r ON r.{id} = {table}.id
+      INNER JOIN matches ON matches.id = r.archive_id
+      GROUP BY {table}.id{group} ORDER BY count DESC, {table}.name ASC LIMIT "#,
+      table = tag_type.table(),
+      relation = tag_type.relation(),
+      id = tag_type.id()
+    ))
+    .push_bind(CONFIG.search.facet_limit)
+    .push(")");
   }
 
-  qb.push(", ARRAY_POSITION(")
-    .push_bind(&ids)
-    .push(",id) AS ord");
+  let rows: Vec<(i32, i64, String, String, Option<String>, i64)> =
+    qb.build_query_as().fetch_all(pool).await?;
 
-  qb.push(" FROM archives WHERE id = ANY(")
-    .push_bind(&ids)
-    .push(") ORDER BY ord");
+  let mut facets: HashMap<TagType, Vec<TaxonomyFacet>> = HashMap::new();
 
-  let rows = qb.build().fetch_all(pool).await?;
+  for (i, id, slug, name, namespace, count) in rows {
+    facets
+      .entry(tag_types[i as usize])
+      .or_default()
+      .push(TaxonomyFacet {
+        taxonomy: TaxonomyId { id, slug, name },
+        namespace,
+        count,
+      });
+  }
 
-  let archives = rows
-    .iter()
-    .map(|row| {
-      let cover = row
-        .try_get::<Json<_>, _>(4)
-        .map(|r| r.0)
-        .unwrap_or(None)
-        .filter(|cover: &ImageDimensions| cover.width.is_some() || cover.height.is_some());
-
-      ArchiveListItem {
-        id: row.get(0),
-        slug: row.get(1),
-        hash: row.get(2),
-        title: row.get(3),
-        cover,
-        artists: row.get::<Json<_>, _>(5).0,
-        circles: row.get::<Json<_>, _>(6).0,
-        magazines: row.get::<Json<_>, _>(7).0,
-        events: row.get::<Json<_>, _>(8).0,
-        publishers: row.get::<Json<_>, _>(9).0,
-        parodies: row.get::<Json<_>, _>(10).0,
-        tags: row.get::<Json<_>, _>(11).0,
-      }
-    })
-    .collect();
+  Ok(facets)
+}
+
+pub async fn add_favorite(user_id: i64, archive_id: i64, pool: &PgPool) -> Result<(), sqlx::Error> {
+  sqlx::query(
+    r#"INSERT INTO favorites (user_id, archive_id) VALUES ($1, $2)
+    ON CONFLICT (user_id, archive_id) DO NOTHING"#,
+  )
+  .bind(user_id)
+  .bind(archive_id)
+  .execute(pool)
+  .await?;
+
+  Ok(())
+}
+
+pub async fn remove_favorite(
+  user_id: i64,
+  archive_id: i64,
+  pool: &PgPool,
+) -> Result<(), sqlx::Error> {
+  sqlx::query(r#"DELETE FROM favorites WHERE user_id = $1 AND archive_id = $2"#)
+    .bind(user_id)
+    .bind(archive_id)
+    .execute(pool)
+    .await?;
 
-  Ok((archives, count))
+  Ok(())
 }
 
 async fn copy_archive(
   old_hash: String,
   new_hash: String,
   transaction: &mut Transaction<'_, Postgres>,
-) -> anyhow::Result<i64> {
-  let rec = sqlx::query!(
-    r#"SELECT slug, title, description, path, pages, size, thumbnail, language, released_at, has_metadata FROM archives WHERE hash = $1"#,
-    old_hash
+) -> Result<i64, sqlx::Error> {
+  let new_id = sqlx::query_scalar!(
+    r#"INSERT INTO archives (
+      slug, title, description, path, hash, pages, size, thumbnail, alt_thumbnail, language, rating, released_at, has_metadata
+    ) SELECT
+      slug, title, description, path, $2, pages, size, thumbnail, alt_thumbnail, language, rating, released_at, has_metadata
+    FROM archives WHERE hash = $1 RETURNING id"#,
+    old_hash,
+    new_hash
   )
   .fetch_one(&mut **transaction)
   .await?;
 
-  let new_id = sqlx::query_scalar!(
-      r#"INSERT INTO archives (
-        slug, title, description, path, hash, pages, size, thumbnail, language, released_at, has_metadata
-      ) VALUES (
-       $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11
-      ) RETURNING id"#,
-    rec.slug,
-    rec.title,
-    rec.description,
-    rec.path,
-    new_hash,
-    rec.pages,
-    rec.size,
-    rec.thumbnail,
-    rec.language,
-    rec.released_at,
-    rec.has_metadata
-  ).fetch_one(&mut **transaction).await?;
-
   Ok(new_id)
 }
 
+/// Copies the taxonomies, tags, sources and images of an archive to another one.
+async fn copy_relations(
+  from_id: i64,
+  to_id: i64,
+  transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+  for tag_type in [
+    TagType::Artist,
+    TagType::Circle,
+    TagType::Magazine,
+    TagType::Event,
+    TagType::Publisher,
+    TagType::Parody,
+  ] {
+    sqlx::query(&format!(
+      r#"INSERT INTO {relation} (archive_id, {id}) SELECT $2, {id} FROM {relation} WHERE archive_id = $1"#,
+      relation = tag_type.relation(),
+      id = tag_type.id()
+    ))
+    .bind(from_id)
+    .bind(to_id)
+    .execute(&mut **transaction)
+    .await?;
+  }
+
+  for sql in [
+    r#"INSERT INTO archive_tags (archive_id, tag_id, namespace)
+    SELECT $2, tag_id, namespace FROM archive_tags WHERE archive_id = $1"#,
+    r#"INSERT INTO archive_sources (archive_id, name, url)
+    SELECT $2, name, url FROM archive_sources WHERE archive_id = $1"#,
+    r#"INSERT INTO archive_images (archive_id, filename, page_number, width, height, is_spread)
+    SELECT $2, filename, page_number, width, height, is_spread FROM archive_images WHERE archive_id = $1"#,
+  ] {
+    sqlx::query(sql)
+      .bind(from_id)
+      .bind(to_id)
+      .execute(&mut **transaction)
+      .await?;
+  }
+
+  Ok(())
+}
+
 async fn upsert_relations(
   data: Relations,
   archive_id: i64,
@@ -825,6 +1955,31 @@ async fn upsert_relations(
     upsert_images(images, archive_id, transaction).await?;
   }
 
+  if let Some(cover) = data.cover {
+    upsert_cover_dimensions(cover, archive_id, transaction).await?;
+  }
+
+  Ok(())
+}
+
+/// Fills in the dimensions of the cover page when they haven't been calculated yet.
+async fn upsert_cover_dimensions(
+  cover: ImageDimensions,
+  archive_id: i64,
+  transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+  sqlx::query(
+    r#"UPDATE archive_images SET width = $2, height = $3, is_spread = COALESCE(is_spread, $2 > $3)
+    FROM archives WHERE archives.id = $1 AND archive_images.archive_id = archives.id
+    AND archive_images.page_number = archives.thumbnail
+    AND (archive_images.width IS NULL OR archive_images.height IS NULL)"#,
+  )
+  .bind(archive_id)
+  .bind(cover.width)
+  .bind(cover.height)
+  .execute(&mut **transaction)
+  .await?;
+
   Ok(())
 }
 
@@ -832,35 +1987,178 @@ pub async fn upsert_archive(
   data: UpsertArchiveData,
   pool: &PgPool,
   mp: &MultiProgress,
-) -> anyhow::Result<i64> {
-  let mut path_link = None;
-
+) -> Result<ArchiveId, DbError> {
   let mut transaction = pool.begin().await?;
 
-  let rec = sqlx::query!(
-    r#"SELECT id, slug, path, hash FROM archives WHERE (id = $1 OR path = $2 OR hash = $3) AND deleted_at IS NULL"#,
-    data.id,
-    data.path,
-    data.hash
-  )
-  .fetch_optional(&mut *transaction)
-  .await?;
+  let (archive, path_link) = upsert_archive_data(data, &mut transaction, mp).await?;
 
-  let archive_id = if let Some(rec) = rec {
-    if let Some(hash) = data.hash {
-      if hash != rec.hash {
-        mp.suspend(|| {
-          warn!(
-            target: "db::upsert_archive",
-            "Hash mismatch - OLD: {}, NEW: {}", rec.hash, hash
-          );
-          warn!(
-            target: "db::upsert_archive",
+  transaction.commit().await?;
+
+  if let Some(path) = path_link {
+    link_archive(archive.id, &path, mp);
+  }
+
+  Ok(archive)
+}
+
+/// Upserts a batch of archives, returning their IDs in the same order.
+///
+/// The batch is split in transactions of `database.batch_size` archives. When an archive fails,
+/// the archives of its chunk are rolled back, while the previous chunks stay committed.
+pub async fn upsert_archives(
+  data: Vec<UpsertArchiveData>,
+  pool: &PgPool,
+  mp: &MultiProgress,
+) -> anyhow::Result<Vec<i64>> {
+  let pb = ProgressBar::new(data.len() as u64);
+
+  pb.set_style(
+    ProgressStyle::with_template("[{elapsed_precise}] {bar:40.green/white} {pos:>7}/{len:7}")
+      .unwrap(),
+  );
+
+  mp.add(pb.clone());
+
+  let mut ids = Vec::with_capacity(data.len());
+  let mut data = data.into_iter().enumerate().peekable();
+
+  while data.peek().is_some() {
+    let mut transaction = pool.begin().await?;
+    let mut links = vec![];
+
+    for (i, item) in data.by_ref().take(CONFIG.database.batch_size.max(1)) {
+      let path = item.path.clone();
+
+      let (archive, path_link) = upsert_archive_data(item, &mut transaction, mp)
+        .await
+        .map_err(|err| {
+          anyhow!(
+            "Failed to upsert the archive at index {i} ({}): {err}",
+            path.unwrap_or_default()
+          )
+        })?;
+
+      ids.push(archive.id);
+
+      if let Some(path) = path_link {
+        links.push((archive.id, path));
+      }
+
+      pb.inc(1);
+    }
+
+    transaction.commit().await?;
+
+    for (id, path) in links {
+      link_archive(id, &path, mp);
+    }
+  }
+
+  pb.finish_and_clear();
+
+  Ok(ids)
+}
+
+/// Tags as `(name, namespace)` pairs.
+type NamespacedTags = Vec<(String, String)>;
+
+/// Applies `allowed_namespaces` to the given `(name, namespace)` tags. Tags in any other namespace
+/// are moved to `namespace_fallback`, or dropped when it's unset. Along with the tags to save,
+/// returns the ones that weren't allowed as they were given.
+fn filter_tag_namespaces(
+  tags: NamespacedTags,
+  metadata: &Metadata,
+) -> (NamespacedTags, NamespacedTags) {
+  let Some(allowed) = &metadata.allowed_namespaces else {
+    return (tags, vec![]);
+  };
+
+  let mut kept = vec![];
+  let mut disallowed = vec![];
+
+  for (name, namespace) in tags {
+    let normalized = normalize_namespace(&namespace);
+
+    if allowed.contains(&normalized) {
+      kept.push((name, normalized));
+    } else {
+      if let Some(fallback) = &metadata.namespace_fallback {
+        kept.push((name.clone(), fallback.clone()));
+      }
+
+      disallowed.push((name, namespace));
+    }
+  }
+
+  (kept, disallowed)
+}
+
+/// Describes the tags left out by `filter_tag_namespaces`, for logging.
+fn disallowed_tags_message(disallowed: &[(String, String)]) -> String {
+  let tags = disallowed
+    .iter()
+    .map(|(name, namespace)| format!("'{namespace}:{name}'"))
+    .join(", ");
+
+  match &CONFIG.metadata.namespace_fallback {
+    Some(fallback) => {
+      format!("Tags in namespaces that aren't allowed saved as '{fallback}': {tags}")
+    }
+    None => format!("Skipping tags in namespaces that aren't allowed: {tags}"),
+  }
+}
+
+/// Upserts the archive inside the given transaction. Along with the archive, returns the path to
+/// link once the transaction is committed.
+async fn upsert_archive_data(
+  mut data: UpsertArchiveData,
+  transaction: &mut Transaction<'_, Postgres>,
+  mp: &MultiProgress,
+) -> Result<(ArchiveId, Option<String>), DbError> {
+  let mut path_link = None;
+
+  if let Some(tags) = data.tags.take() {
+    let (tags, disallowed) = filter_tag_namespaces(tags, &CONFIG.metadata);
+
+    if !disallowed.is_empty() {
+      mp.suspend(|| {
+        warn!(
+          target: "db::upsert_archive",
+          "{}", disallowed_tags_message(&disallowed)
+        )
+      });
+    }
+
+    data.tags = Some(tags);
+  }
+
+  if let Some(cover) = &data.cover {
+    if !matches!((cover.width, cover.height), (Some(width), Some(height)) if width > 0 && height > 0)
+    {
+      return Err(DbError::Validation(
+        "Cover dimensions must be positive".to_string(),
+      ));
+    }
+  }
+
+  let rec = find_upsert_target(&data, &mut **transaction).await?;
+  let outcome = upsert_outcome(&data, rec.as_ref())?;
+
+  let (archive_id, slug) = if let Some(rec) = rec {
+    if let UpsertOutcome::ReplaceCopy { .. } = outcome {
+      if let Some(hash) = data.hash {
+        mp.suspend(|| {
+          warn!(
+            target: "db::upsert_archive",
+            "Hash mismatch - OLD: {}, NEW: {}", rec.hash, hash
+          );
+          warn!(
+            target: "db::upsert_archive",
             "A new copy of the old archive will be created and it will replace the old one."
           );
         });
 
-        let new_id = copy_archive(rec.hash, hash, &mut transaction).await?;
+        let new_id = copy_archive(rec.hash, hash, transaction).await?;
 
         upsert_relations(
           Relations {
@@ -873,9 +2171,10 @@ pub async fn upsert_archive(
             tags: data.tags,
             sources: data.sources,
             images: data.images,
+            cover: data.cover,
           },
           new_id,
-          &mut transaction,
+          transaction,
         )
         .await?;
 
@@ -883,17 +2182,16 @@ pub async fn upsert_archive(
           "UPDATE archives SET deleted_at = NOW() WHERE id = $1",
           rec.id,
         )
-        .execute(&mut *transaction)
+        .execute(&mut **transaction)
         .await?;
 
-        transaction.commit().await?;
-
-        utils::create_symlink(
-          &rec.path,
-          &CONFIG.directories.links.join(new_id.to_string()),
-        )?;
-
-        return Ok(new_id);
+        return Ok((
+          ArchiveId {
+            id: new_id,
+            slug: rec.slug,
+          },
+          Some(rec.path),
+        ));
       }
     }
 
@@ -904,6 +2202,7 @@ pub async fn upsert_archive(
     }
 
     if let Some(slug) = data.slug {
+      let slug = unique_slug(&slug, Some(rec.id), transaction).await?;
       qb.push(" slug = ").push_bind(slug).push(",");
     }
 
@@ -927,14 +2226,33 @@ pub async fn upsert_archive(
       qb.push(" size = ").push_bind(size).push(",");
     }
 
+    // Covers are clamped to the page count, the given one or the stored one otherwise
     if let Some(thumbnail) = data.thumbnail {
-      qb.push(" thumbnail = ").push_bind(thumbnail).push(",");
+      qb.push(" thumbnail = LEAST(GREATEST(")
+        .push_bind(thumbnail)
+        .push(", 1), GREATEST(COALESCE(")
+        .push_bind(data.pages)
+        .push(", pages, 1), 1)),");
+    }
+
+    if let Some(alt_thumbnail) = data.alt_thumbnail {
+      qb.push(" alt_thumbnail = LEAST(GREATEST(")
+        .push_bind(alt_thumbnail)
+        .push(", 1), GREATEST(COALESCE(")
+        .push_bind(data.pages)
+        .push(", pages, 1), 1)),");
     }
 
     qb.push(" language = ")
       .push_bind(data.language.clone())
       .push(",");
 
+    if let Some(rating) = data.rating {
+      qb.push(" rating = ")
+        .push_bind(rating.to_string())
+        .push(",");
+    }
+
     if let Some(released_at) = data.released_at {
       qb.push(" released_at = ").push_bind(released_at).push(",");
     }
@@ -953,11 +2271,14 @@ pub async fn upsert_archive(
 
     qb.push(" WHERE id = ")
       .push_bind(rec.id)
-      .push(" RETURNING id");
+      .push(" RETURNING slug");
 
-    qb.build().fetch_one(&mut *transaction).await?;
+    let slug: String = qb
+      .build_query_scalar()
+      .fetch_one(&mut **transaction)
+      .await?;
 
-    rec.id
+    (rec.id, slug)
   } else if let (Some(title), Some(path), Some(hash), Some(pages), Some(size), Some(thumbnail)) = (
     data.title,
     data.path,
@@ -967,31 +2288,38 @@ pub async fn upsert_archive(
     data.thumbnail,
   ) {
     let slug = data.slug.unwrap_or(slugify(&title));
+    let slug = unique_slug(&slug, None, transaction).await?;
+
+    let rating = data.rating.or(CONFIG.metadata.default_rating);
 
     let id = sqlx::query_scalar!(
-    r#"INSERT INTO archives (
-      slug, title, description, path, hash, pages, size, thumbnail, language, released_at, has_metadata
-    ) VALUES (
-     $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11
-    ) RETURNING id"#,
-    slug,
-    title,
-    data.description,
-    path,
-    hash,
-    pages,
-    size,
-    thumbnail,
-    data.language,
-    data.released_at,
-    data.has_metadata.unwrap_or_default()
-  ).fetch_one(&mut *transaction).await?;
+      r#"INSERT INTO archives (
+        slug, title, description, path, hash, pages, size, thumbnail, alt_thumbnail, language, rating, released_at, has_metadata
+      ) VALUES (
+       $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13
+      ) RETURNING id"#,
+      slug,
+      title,
+      data.description,
+      path,
+      hash,
+      pages,
+      size,
+      clamp_page(thumbnail, pages),
+      data.alt_thumbnail.map(|page| clamp_page(page, pages)),
+      data.language,
+      rating.map(|rating| rating.to_string()),
+      data.released_at,
+      data.has_metadata.unwrap_or_default()
+    )
+    .fetch_one(&mut **transaction)
+    .await?;
 
     path_link = Some(path);
 
-    id
+    (id, slug)
   } else {
-    return Err(anyhow!("Insufficient archive data to insert"));
+    unreachable!("upsert_outcome only inserts archives with the required data")
   };
 
   upsert_relations(
@@ -1005,22 +2333,135 @@ pub async fn upsert_archive(
       tags: data.tags,
       sources: data.sources,
       images: data.images,
+      cover: data.cover,
     },
     archive_id,
-    &mut transaction,
+    transaction,
   )
   .await?;
 
-  transaction.commit().await?;
+  Ok((
+    ArchiveId {
+      id: archive_id,
+      slug,
+    },
+    path_link,
+  ))
+}
 
-  if let Some(path) = path_link {
-    utils::create_symlink(
-      &path,
-      &CONFIG.directories.links.join(archive_id.to_string()),
-    )?;
+/// Keeps a cover page number within the pages of the archive.
+fn clamp_page(page: i16, pages: i16) -> i16 {
+  page.clamp(1, pages.max(1))
+}
+
+/// Links the archive file into the links directory, unless disabled by `create_links`.
+///
+/// The archive is already committed when this runs, so a failure is only reported instead of
+/// failing the whole upsert.
+fn link_archive(archive_id: i64, path: &str, mp: &MultiProgress) {
+  if !CONFIG.directories.create_links {
+    return;
+  }
+
+  if let Err(err) = utils::create_symlink(
+    &path,
+    &CONFIG.directories.links.join(archive_id.to_string()),
+  ) {
+    mp.suspend(|| {
+      warn!(
+        target: "db::upsert_archive",
+        "Couldn't create the symbolic link for archive ID {archive_id}: {err}"
+      )
+    });
+  }
+}
+
+async fn find_upsert_target<'c, E>(
+  data: &UpsertArchiveData,
+  executor: E,
+) -> Result<Option<UpsertTarget>, sqlx::Error>
+where
+  E: sqlx::Executor<'c, Database = Postgres>,
+{
+  sqlx::query_as!(
+    UpsertTarget,
+    r#"SELECT id, slug, path, hash FROM archives WHERE (id = $1 OR path = $2 OR hash = $3) AND deleted_at IS NULL"#,
+    data.id,
+    data.path,
+    data.hash
+  )
+  .fetch_optional(executor)
+  .await
+}
+
+/// Decides what `upsert_archive` does with the data, given the archive it matched.
+fn upsert_outcome(
+  data: &UpsertArchiveData,
+  rec: Option<&UpsertTarget>,
+) -> Result<UpsertOutcome, DbError> {
+  match rec {
+    Some(rec) => match &data.hash {
+      Some(hash) if *hash != rec.hash => Ok(UpsertOutcome::ReplaceCopy { old_id: rec.id }),
+      _ => Ok(UpsertOutcome::Update(rec.id)),
+    },
+    None => match (
+      &data.title,
+      &data.path,
+      &data.hash,
+      data.pages,
+      data.size,
+      data.thumbnail,
+    ) {
+      (Some(_), Some(_), Some(_), Some(_), Some(_), Some(_)) => Ok(UpsertOutcome::Insert),
+      _ => Err(DbError::Validation(
+        "Insufficient archive data to insert".to_string(),
+      )),
+    },
+  }
+}
+
+/// Tells what `upsert_archive` would do with the data, without writing anything.
+pub async fn preview_upsert_archive(
+  data: &UpsertArchiveData,
+  pool: &PgPool,
+) -> Result<UpsertOutcome, DbError> {
+  let rec = find_upsert_target(data, pool).await?;
+
+  upsert_outcome(data, rec.as_ref())
+}
+
+/// Returns the given slug or, if it's already used by another archive, the first free one
+/// with a numeric suffix (`-2`, `-3`, ...).
+async fn unique_slug(
+  slug: &str,
+  exclude_id: Option<i64>,
+  transaction: &mut Transaction<'_, Postgres>,
+) -> Result<String, sqlx::Error> {
+  sqlx::query(r#"SELECT pg_advisory_xact_lock(hashtext($1))"#)
+    .bind(slug)
+    .execute(&mut **transaction)
+    .await?;
+
+  let taken: Vec<String> = sqlx::query_scalar(
+    r#"SELECT slug FROM archives
+    WHERE (slug = $1 OR slug LIKE $2) AND id IS DISTINCT FROM $3 AND deleted_at IS NULL"#,
+  )
+  .bind(slug)
+  .bind(format!("{slug}-%"))
+  .bind(exclude_id)
+  .fetch_all(&mut **transaction)
+  .await?;
+
+  if !taken.iter().any(|taken| taken == slug) {
+    return Ok(slug.to_string());
   }
 
-  Ok(archive_id)
+  Ok(
+    (2..)
+      .map(|i| format!("{slug}-{i}"))
+      .find(|candidate| !taken.contains(candidate))
+      .unwrap(),
+  )
 }
 
 async fn upsert_taxonomy(
@@ -1146,24 +2587,18 @@ async fn upsert_taxonomy(
   Ok(())
 }
 
-async fn upsert_tags(
+/// Normalizes the given `(name, namespace)` tags and inserts the ones missing from the tags table.
+/// Returns each tag once along with its ID.
+async fn insert_tags(
   tags: Vec<(String, String)>,
-  archive_id: i64,
   transaction: &mut Transaction<'_, Postgres>,
-) -> Result<(), sqlx::Error> {
+) -> Result<Vec<(Tag, i64)>, sqlx::Error> {
   #[derive(sqlx::FromRow, Debug)]
   struct TagRow {
     id: i64,
     slug: String,
   }
 
-  #[derive(sqlx::FromRow, Debug)]
-  struct RelationRow {
-    tag_id: i64,
-    slug: String,
-    namespace: String,
-  }
-
   let archive_tags = tags
     .into_iter()
     .map(|(name, namespace)| {
@@ -1172,9 +2607,10 @@ async fn upsert_tags(
       Tag {
         slug,
         name,
-        namespace,
+        namespace: normalize_namespace(&namespace),
       }
     })
+    .unique_by(|tag| (tag.slug.to_string(), tag.namespace.to_string()))
     .collect_vec();
 
   let mut tags = sqlx::query_as!(
@@ -1211,6 +2647,31 @@ async fn upsert_tags(
     db_tags.append(&mut new_tags);
   }
 
+  Ok(
+    archive_tags
+      .into_iter()
+      .map(|tag| {
+        let id = db_tags.iter().find(|t| t.slug.eq(&tag.slug)).unwrap().id;
+        (tag, id)
+      })
+      .collect(),
+  )
+}
+
+async fn upsert_tags(
+  tags: Vec<(String, String)>,
+  archive_id: i64,
+  transaction: &mut Transaction<'_, Postgres>,
+) -> Result<(), sqlx::Error> {
+  #[derive(sqlx::FromRow, Debug)]
+  struct RelationRow {
+    tag_id: i64,
+    slug: String,
+    namespace: String,
+  }
+
+  let archive_tags = insert_tags(tags, transaction).await?;
+
   let archive_tags_relation = sqlx::query_as!(
     RelationRow,
     r#"SELECT tag_id, slug, namespace FROM archive_tags
@@ -1225,7 +2686,7 @@ async fn upsert_tags(
     .filter(|relation| {
       !archive_tags
         .iter()
-        .any(|tag| tag.slug == relation.slug && tag.namespace == relation.namespace)
+        .any(|(tag, _)| tag.slug == relation.slug && tag.namespace == relation.namespace)
     })
     .collect_vec();
 
@@ -1242,31 +2703,28 @@ async fn upsert_tags(
 
   let relations_to_insert = archive_tags
     .iter()
-    .filter(|tag| {
+    .filter(|(tag, _)| {
       !archive_tags_relation
         .iter()
         .any(|relation| relation.slug == tag.slug && relation.namespace == tag.namespace)
     })
     .collect_vec();
 
-  let tag_ids = relations_to_insert
-    .iter()
-    .map(|tag| db_tags.iter().find(|t| t.slug.eq(&tag.slug)).unwrap().id)
-    .collect_vec();
-
   sqlx::query!(
     r#"INSERT INTO archive_tags (archive_id, tag_id, namespace) SELECT * FROM UNNEST($1::bigint[], $2::bigint[], $3::text[])"#,
-    &vec![archive_id; tag_ids.len()],
-    &tag_ids,
+    &vec![archive_id; relations_to_insert.len()],
+    &relations_to_insert.iter().map(|(_, id)| *id).collect_vec(),
     &relations_to_insert
       .into_iter()
-      .map(|tag| tag.namespace.clone())
+      .map(|(tag, _)| tag.namespace.clone())
       .collect_vec()
   ).execute(&mut **transaction).await?;
 
   Ok(())
 }
 
+/// Source names are matched case-insensitively, so `E-Hentai` and `e-hentai` are the same source.
+/// An existing source keeps its name, so the `ON CONFLICT` target still matches.
 async fn upsert_sources(
   sources: Vec<ArchiveSource>,
   archive_id: i64,
@@ -1281,34 +2739,72 @@ async fn upsert_sources(
   .fetch_all(&mut **transaction)
   .await?;
 
+  let same_name = |a: &str, b: &str| a.to_lowercase() == b.to_lowercase();
+
+  // Case variants are collapsed, preferring the one with an URL
+  let mut normalized: Vec<ArchiveSource> = vec![];
+
+  for source in sources {
+    let name = source.name.trim().to_string();
+    let url = source
+      .url
+      .map(|url| url.trim().to_string())
+      .filter(|url| !url.is_empty());
+
+    if name.is_empty() {
+      continue;
+    }
+
+    let name = existing_sources
+      .iter()
+      .find(|existing| same_name(&existing.name, &name))
+      .map(|existing| existing.name.clone())
+      .unwrap_or(name);
+
+    match normalized
+      .iter_mut()
+      .find(|normalized| same_name(&normalized.name, &name))
+    {
+      Some(normalized) if normalized.url.is_none() => normalized.url = url,
+      Some(_) => {}
+      None => normalized.push(ArchiveSource { name, url }),
+    }
+  }
+
+  let sources = normalized;
+
   if !merge {
     let relations_to_delete = existing_sources
       .iter()
       .filter(|relation| {
         !sources
           .iter()
-          .any(|source| source.name == relation.name && source.url == relation.url)
+          .any(|source| same_name(&source.name, &relation.name))
       })
       .collect_vec();
 
     for relation in relations_to_delete {
       sqlx::query!(
-        r#"DELETE FROM archive_sources WHERE archive_id = $1 AND name = $2 AND url = $3"#,
+        r#"DELETE FROM archive_sources WHERE archive_id = $1 AND name = $2"#,
         archive_id,
-        relation.name,
-        relation.url
+        relation.name
       )
       .execute(&mut **transaction)
       .await?;
     }
   }
 
+  // An URL that only differs in its scheme or `www.` is kept as it was
+  let same_url = |a: &Option<String>, b: &Option<String>| {
+    a.as_deref().map(utils::normalize_source_url) == b.as_deref().map(utils::normalize_source_url)
+  };
+
   let relations_to_insert = sources
     .iter()
     .filter(|source| {
       !existing_sources
         .iter()
-        .any(|relation| relation.name == source.name && relation.url == source.url)
+        .any(|relation| relation.name == source.name && same_url(&relation.url, &source.url))
     })
     .collect_vec();
 
@@ -1332,47 +2828,2565 @@ async fn upsert_images(
   archive_id: i64,
   transaction: &mut Transaction<'_, Postgres>,
 ) -> Result<(), sqlx::Error> {
-  let existing_images = sqlx::query_as!(
-    ArchiveImage,
-    r#"SELECT filename, page_number, width, height FROM archive_images WHERE archive_id = $1"#,
-    archive_id
+  // A page can only be upserted once per statement, the last entry wins like before
+  let images = images
+    .into_iter()
+    .rev()
+    .unique_by(|image| image.page_number)
+    .collect_vec();
+
+  let page_numbers = images.iter().map(|image| image.page_number).collect_vec();
+
+  sqlx::query!(
+    r#"DELETE FROM archive_images WHERE archive_id = $1 AND page_number <> ALL($2)"#,
+    archive_id,
+    &page_numbers
   )
-  .fetch_all(&mut **transaction)
+  .execute(&mut **transaction)
   .await?;
 
-  let relations_to_delete = existing_images
+  if images.is_empty() {
+    return Ok(());
+  }
+
+  let filenames = images
     .iter()
-    .filter(|relation| {
-      !images
-        .iter()
-        .any(|source| source.page_number == relation.page_number)
+    .map(|image| image.filename.clone())
+    .collect_vec();
+  let widths = images.iter().map(|image| image.width).collect_vec();
+  let heights = images.iter().map(|image| image.height).collect_vec();
+  let spreads = images
+    .iter()
+    .map(|image| {
+      image.is_spread.or(match (image.width, image.height) {
+        (Some(width), Some(height)) => Some(width > height),
+        _ => None,
+      })
     })
     .collect_vec();
 
-  for relation in relations_to_delete {
-    sqlx::query!(
-      r#"DELETE FROM archive_images WHERE archive_id = $1 AND page_number = $2"#,
-      archive_id,
-      relation.page_number,
-    )
-    .execute(&mut **transaction)
-    .await?;
+  // Arrays are checked without NULL elements, so the nullable ones need a type override
+  sqlx::query!(
+    r#"INSERT INTO archive_images (archive_id, filename, page_number, width, height, is_spread)
+    SELECT $1, * FROM UNNEST($2::text[], $3::smallint[], $4::smallint[], $5::smallint[], $6::boolean[])
+    ON CONFLICT (archive_id, page_number) DO UPDATE
+    SET filename = EXCLUDED.filename, width = EXCLUDED.width, height = EXCLUDED.height, is_spread = EXCLUDED.is_spread"#,
+    archive_id,
+    &filenames,
+    &page_numbers,
+    &widths as &[Option<i16>],
+    &heights as &[Option<i16>],
+    &spreads as &[Option<bool>]
+  )
+  .execute(&mut **transaction)
+  .await?;
+
+  Ok(())
+}
+
+pub async fn normalize_tag_namespaces(pool: &PgPool) -> Result<u64, sqlx::Error> {
+  let mut transaction = pool.begin().await?;
+
+  let namespaces: Vec<String> =
+    sqlx::query_scalar(r#"SELECT DISTINCT namespace FROM archive_tags"#)
+      .fetch_all(&mut *transaction)
+      .await?;
+
+  let mut affected = 0;
+  let mut archive_ids = HashSet::new();
+
+  for namespace in namespaces {
+    let normalized = normalize_namespace(&namespace);
+
+    if normalized == namespace {
+      continue;
+    }
+
+    let updated = move_tag_namespace(&namespace, &normalized, &mut transaction).await?;
+
+    affected += updated.len() as u64;
+    archive_ids.extend(updated);
   }
 
-  for image in images {
-    sqlx::query!(
-      r#"INSERT INTO archive_images (archive_id, filename, page_number, width, height)
-      VALUES ($1, $2, $3, $4, $5) ON CONFLICT (archive_id, page_number) DO UPDATE
-      SET filename = EXCLUDED.filename, width = EXCLUDED.width, height = EXCLUDED.height"#,
-      archive_id,
-      image.filename,
-      image.page_number,
-      image.width,
-      image.height
-    )
-    .execute(&mut **transaction)
-    .await?;
+  touch_archives(&archive_ids.into_iter().collect_vec(), &mut *transaction).await?;
+
+  transaction.commit().await?;
+
+  Ok(affected)
+}
+
+/// Moves every archive tag in the `from` namespace to the `to` namespace, which is normalized
+/// first. Returns the amount of moved archive tags.
+pub async fn reassign_namespace(from: &str, to: &str, pool: &PgPool) -> Result<u64, sqlx::Error> {
+  let to = normalize_namespace(to);
+
+  if from == to {
+    return Ok(0);
   }
 
-  Ok(())
+  let mut transaction = pool.begin().await?;
+
+  let archive_ids = move_tag_namespace(from, &to, &mut transaction).await?;
+
+  touch_archives(
+    &archive_ids.iter().copied().unique().collect_vec(),
+    &mut *transaction,
+  )
+  .await?;
+
+  transaction.commit().await?;
+
+  Ok(archive_ids.len() as u64)
+}
+
+/// Archives that already have the tag in the new namespace keep that one. Returns the archive ID
+/// of each moved archive tag.
+async fn move_tag_namespace(
+  from: &str,
+  to: &str,
+  transaction: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<i64>, sqlx::Error> {
+  sqlx::query(
+    r#"DELETE FROM archive_tags a WHERE a.namespace = $1 AND EXISTS (
+      SELECT 1 FROM archive_tags b
+      WHERE b.archive_id = a.archive_id AND b.tag_id = a.tag_id AND b.namespace = $2
+    )"#,
+  )
+  .bind(from)
+  .bind(to)
+  .execute(&mut **transaction)
+  .await?;
+
+  sqlx::query_scalar(
+    r#"UPDATE archive_tags SET namespace = $2 WHERE namespace = $1 RETURNING archive_id"#,
+  )
+  .bind(from)
+  .bind(to)
+  .fetch_all(&mut **transaction)
+  .await
+}
+
+/// Bumps `updated_at` of the given archives, for changes made only to their relations.
+pub async fn touch_archives<'c, E>(ids: &[i64], executor: E) -> Result<u64, sqlx::Error>
+where
+  E: sqlx::Executor<'c, Database = Postgres>,
+{
+  if ids.is_empty() {
+    return Ok(0);
+  }
+
+  let affected = sqlx::query(r#"UPDATE archives SET updated_at = NOW() WHERE id = ANY($1)"#)
+    .bind(ids)
+    .execute(executor)
+    .await?
+    .rows_affected();
+
+  Ok(affected)
+}
+
+pub async fn find_similar_titles(
+  threshold: f32,
+  pool: &PgPool,
+) -> Result<Vec<(ArchiveId, ArchiveId, f32)>, sqlx::Error> {
+  let mut transaction = pool.begin().await?;
+
+  sqlx::query(r#"SELECT set_config('pg_trgm.similarity_threshold', $1::text, true)"#)
+    .bind(threshold)
+    .execute(&mut *transaction)
+    .await?;
+
+  let rows = sqlx::query(
+    r#"SELECT a.id, a.slug, b.id, b.slug, similarity(a.title, b.title)::real
+    FROM archives a INNER JOIN archives b ON a.id < b.id AND a.title % b.title
+    WHERE a.deleted_at IS NULL AND b.deleted_at IS NULL
+    ORDER BY 5 DESC, a.id ASC, b.id ASC"#,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+
+  transaction.commit().await?;
+
+  Ok(
+    rows
+      .iter()
+      .map(|row| {
+        (
+          ArchiveId {
+            id: row.get(0),
+            slug: row.get(1),
+          },
+          ArchiveId {
+            id: row.get(2),
+            slug: row.get(3),
+          },
+          row.get(4),
+        )
+      })
+      .collect(),
+  )
+}
+
+pub async fn rebuild_fts(archive_id: Option<i64>, pool: &PgPool) -> anyhow::Result<u64> {
+  let ids: Vec<i64> = if let Some(archive_id) = archive_id {
+    vec![archive_id]
+  } else {
+    sqlx::query_scalar(r#"SELECT id FROM archives ORDER BY id ASC"#)
+      .fetch_all(pool)
+      .await?
+  };
+
+  index_fts(&ids, pool).await
+}
+
+/// Lists the archives without a search index row. `search` joins on it, so they never show up.
+pub async fn archives_missing_fts(pool: &PgPool) -> Result<Vec<ArchiveId>, sqlx::Error> {
+  sqlx::query_as(
+    r#"SELECT archives.id, archives.slug FROM archives
+    LEFT JOIN archive_fts fts ON fts.archive_id = archives.id
+    WHERE fts.archive_id IS NULL AND archives.deleted_at IS NULL ORDER BY archives.id ASC"#,
+  )
+  .fetch_all(pool)
+  .await
+}
+
+pub async fn repair_fts(pool: &PgPool) -> anyhow::Result<u64> {
+  let ids = archives_missing_fts(pool)
+    .await?
+    .into_iter()
+    .map(|archive| archive.id)
+    .collect_vec();
+
+  index_fts(&ids, pool).await
+}
+
+/// Leaves out the tags of `search.excluded_namespaces`, like the search index triggers do.
+const EXCLUDED_NAMESPACES_SQL: &str =
+  " AND r.namespace NOT IN (SELECT namespace FROM fts_excluded_namespaces)";
+
+async fn index_fts(ids: &[i64], pool: &PgPool) -> anyhow::Result<u64> {
+  let tag_types = [
+    TagType::Artist,
+    TagType::Circle,
+    TagType::Magazine,
+    TagType::Event,
+    TagType::Publisher,
+    TagType::Parody,
+    TagType::Tag,
+  ];
+
+  let columns = tag_types.iter().map(|tag_type| tag_type.table()).join(", ");
+  let values = tag_types
+    .iter()
+    .map(|tag_type| {
+      format!(
+        r#"COALESCE((SELECT string_agg({table}.name, ' ') FROM {table} INNER JOIN {relation} r ON r.{id} = {table}.id WHERE r.archive_id = archives.id{excluded}), '')"#,
+        table = tag_type.table(),
+        relation = tag_type.relation(),
+        id = tag_type.id(),
+        excluded = match tag_type {
+          TagType::Tag => EXCLUDED_NAMESPACES_SQL,
+          _ => "",
+        }
+      )
+    })
+    .join(",\n");
+  let updates = tag_types
+    .iter()
+    .map(|tag_type| format!("{table} = EXCLUDED.{table}", table = tag_type.table()))
+    .join(", ");
+
+  let sql = format!(
+    r#"INSERT INTO archive_fts (archive_id, title, description, qualified_tags, {columns})
+    SELECT archives.id, archives.title, COALESCE(archives.description, ''),
+    COALESCE((SELECT string_agg(r.namespace || '-' || REPLACE(tags.name, ' ', '-'), ' ') FROM tags INNER JOIN archive_tags r ON r.tag_id = tags.id WHERE r.archive_id = archives.id{EXCLUDED_NAMESPACES_SQL}), ''),
+    {values}
+    FROM archives WHERE archives.id = ANY($1)
+    ON CONFLICT (archive_id) DO UPDATE SET title = EXCLUDED.title, description = EXCLUDED.description, qualified_tags = EXCLUDED.qualified_tags, {updates}"#
+  );
+
+  let mut affected = 0;
+
+  for chunk in ids.chunks(1000) {
+    affected += sqlx::query(&sql)
+      .bind(chunk)
+      .execute(pool)
+      .await?
+      .rows_affected();
+  }
+
+  Ok(affected)
+}
+
+pub async fn soft_delete_archives(ids: &[i64], pool: &PgPool) -> anyhow::Result<u64> {
+  let deleted: Vec<i64> = sqlx::query_scalar(
+    r#"UPDATE archives SET deleted_at = NOW() WHERE id = ANY($1) AND deleted_at IS NULL RETURNING id"#,
+  )
+  .bind(ids)
+  .fetch_all(pool)
+  .await?;
+
+  if !CONFIG.directories.create_links {
+    return Ok(deleted.len() as u64);
+  }
+
+  for id in &deleted {
+    let link = CONFIG.directories.links.join(id.to_string());
+
+    if let Err(err) = std::fs::remove_file(&link) {
+      warn!(
+        target: "db::soft_delete_archives",
+        "Couldn't remove the symbolic link for archive ID {id}: {err}"
+      );
+    }
+  }
+
+  Ok(deleted.len() as u64)
+}
+
+pub async fn restore_archives(ids: &[i64], pool: &PgPool) -> anyhow::Result<u64> {
+  let restored: Vec<(i64, String)> = sqlx::query_as(
+    r#"UPDATE archives SET deleted_at = NULL WHERE id = ANY($1) AND deleted_at IS NOT NULL RETURNING id, path"#,
+  )
+  .bind(ids)
+  .fetch_all(pool)
+  .await?;
+
+  if !CONFIG.directories.create_links {
+    return Ok(restored.len() as u64);
+  }
+
+  for (id, path) in &restored {
+    if let Err(err) = utils::create_symlink(path, &CONFIG.directories.links.join(id.to_string())) {
+      warn!(
+        target: "db::restore_archives",
+        "Couldn't create the symbolic link for archive ID {id}: {err}"
+      );
+    }
+  }
+
+  Ok(restored.len() as u64)
+}
+
+pub async fn library_stats(pool: &PgPool) -> Result<LibraryStats, sqlx::Error> {
+  sqlx::query_as(
+    r#"SELECT COUNT(*) archives, COALESCE(SUM(pages), 0)::bigint pages, COALESCE(SUM(size), 0)::bigint size,
+    (
+      SELECT COUNT(DISTINCT artist_id) FROM archive_artists
+      INNER JOIN archives ON archives.id = archive_id WHERE deleted_at IS NULL
+    ) artists,
+    (
+      SELECT COUNT(DISTINCT tag_id) FROM archive_tags
+      INNER JOIN archives ON archives.id = archive_id WHERE deleted_at IS NULL
+    ) tags,
+    MIN(released_at) oldest_released_at, MAX(released_at) newest_released_at
+    FROM archives WHERE deleted_at IS NULL"#,
+  )
+  .fetch_one(pool)
+  .await
+}
+
+pub async fn suggest_tags(
+  present_tag_ids: &[i64],
+  limit: i64,
+  pool: &PgPool,
+) -> Result<Vec<(Tag, i64)>, sqlx::Error> {
+  let rows = sqlx::query(
+    r#"SELECT tags.slug, tags.name, r.namespace, COUNT(DISTINCT r.archive_id) count
+    FROM archive_tags r
+    INNER JOIN tags ON tags.id = r.tag_id
+    INNER JOIN archives ON archives.id = r.archive_id AND archives.deleted_at IS NULL
+    WHERE r.tag_id <> ALL($1) AND (
+      CARDINALITY($1) = 0 OR r.archive_id IN (SELECT archive_id FROM archive_tags WHERE tag_id = ANY($1))
+    )
+    GROUP BY tags.id, r.namespace
+    ORDER BY count DESC, tags.name ASC
+    LIMIT $2"#,
+  )
+  .bind(present_tag_ids)
+  .bind(limit)
+  .fetch_all(pool)
+  .await?;
+
+  Ok(
+    rows
+      .iter()
+      .map(|row| {
+        (
+          Tag {
+            slug: row.get(0),
+            name: row.get(1),
+            namespace: row.get(2),
+          },
+          row.get(3),
+        )
+      })
+      .collect(),
+  )
+}
+
+/// Points the archive cover to the page with the given filename and returns its page number.
+pub async fn set_cover_by_filename(
+  archive_id: i64,
+  filename: &str,
+  pool: &PgPool,
+) -> anyhow::Result<i16> {
+  let page_number: i16 = sqlx::query_scalar(
+    r#"UPDATE archives SET thumbnail = archive_images.page_number, updated_at = NOW()
+    FROM archive_images WHERE archives.id = $1
+    AND archive_images.archive_id = archives.id AND archive_images.filename = $2
+    RETURNING archive_images.page_number"#,
+  )
+  .bind(archive_id)
+  .bind(filename)
+  .fetch_optional(pool)
+  .await?
+  .ok_or_else(|| anyhow!("Archive ID {archive_id} has no image named '{filename}'"))?;
+
+  Ok(page_number)
+}
+
+const MIN_HASH_PREFIX: usize = 6;
+
+/// Finds the archives whose hash starts with the given prefix, to match short identifiers.
+pub async fn fetch_archive_by_hash_prefix(
+  prefix: &str,
+  include_deleted: bool,
+  pool: &PgPool,
+) -> anyhow::Result<Vec<ArchiveId>> {
+  let prefix = prefix.trim();
+
+  if prefix.len() < MIN_HASH_PREFIX {
+    return Err(anyhow!(
+      "Hash prefix '{prefix}' is too short, at least {MIN_HASH_PREFIX} characters are required"
+    ));
+  }
+
+  if !prefix.chars().all(|c| c.is_ascii_alphanumeric()) {
+    return Err(anyhow!("Invalid hash prefix '{prefix}'"));
+  }
+
+  let archives = sqlx::query_as(
+    r#"SELECT id, slug FROM archives WHERE hash LIKE $1 || '%' AND ($2 OR deleted_at IS NULL)
+    ORDER BY id ASC"#,
+  )
+  .bind(prefix)
+  .bind(include_deleted)
+  .fetch_all(pool)
+  .await?;
+
+  Ok(archives)
+}
+
+/// Renumbers the given pages of an archive atomically. Pages are moved to temporary negative
+/// numbers first, so swapping page numbers doesn't collide with the unique index.
+pub async fn reorder_pages(
+  archive_id: i64,
+  new_order: Vec<(String, i16)>,
+  pool: &PgPool,
+) -> anyhow::Result<()> {
+  if new_order.iter().map(|(_, page)| page).unique().count() != new_order.len() {
+    return Err(anyhow!("The new page order contains repeated page numbers"));
+  }
+
+  if let Some((filename, page)) = new_order.iter().find(|(_, page)| *page < 1) {
+    return Err(anyhow!("Invalid page number {page} for '{filename}'"));
+  }
+
+  let (filenames, pages): (Vec<String>, Vec<i16>) = new_order.into_iter().unzip();
+
+  let mut transaction = pool.begin().await?;
+
+  let cover: Option<String> = sqlx::query_scalar(
+    r#"SELECT filename FROM archive_images
+    INNER JOIN archives ON archives.id = archive_id AND archives.thumbnail = page_number
+    WHERE archive_id = $1"#,
+  )
+  .bind(archive_id)
+  .fetch_optional(&mut *transaction)
+  .await?;
+
+  let moved = sqlx::query(
+    r#"UPDATE archive_images SET page_number = -page_number
+    WHERE archive_id = $1 AND filename = ANY($2)"#,
+  )
+  .bind(archive_id)
+  .bind(&filenames)
+  .execute(&mut *transaction)
+  .await?
+  .rows_affected();
+
+  if moved != filenames.len() as u64 {
+    return Err(anyhow!(
+      "Some of the given filenames don't belong to archive ID {archive_id}"
+    ));
+  }
+
+  sqlx::query(
+    r#"UPDATE archive_images SET page_number = new_order.page_number
+    FROM UNNEST($2::text[], $3::smallint[]) AS new_order(filename, page_number)
+    WHERE archive_images.archive_id = $1 AND archive_images.filename = new_order.filename"#,
+  )
+  .bind(archive_id)
+  .bind(&filenames)
+  .bind(&pages)
+  .execute(&mut *transaction)
+  .await?;
+
+  sqlx::query(
+    r#"UPDATE archives SET
+    pages = (SELECT COUNT(*) FROM archive_images WHERE archive_id = $1),
+    thumbnail = COALESCE((SELECT page_number FROM archive_images WHERE archive_id = $1 AND filename = $2), thumbnail),
+    updated_at = NOW()
+    WHERE id = $1"#,
+  )
+  .bind(archive_id)
+  .bind(cover)
+  .execute(&mut *transaction)
+  .await?;
+
+  transaction.commit().await?;
+
+  // Thumbnails are named after their page number, so they are generated again when requested
+  let thumbs = CONFIG.directories.thumbs.join(archive_id.to_string());
+
+  if let Err(err) = std::fs::remove_dir_all(&thumbs) {
+    if err.kind() != std::io::ErrorKind::NotFound {
+      warn!(
+        target: "db::reorder_pages",
+        "Couldn't remove the thumbnails for archive ID {archive_id}: {err}"
+      );
+    }
+  }
+
+  Ok(())
+}
+
+/// Finds taxonomies whose slugs only differ by case, grouped by collision. Tags are only
+/// considered colliding when used under the same namespace.
+pub async fn find_taxonomy_slug_collisions(
+  tag_type: TagType,
+  pool: &PgPool,
+) -> Result<Vec<Vec<TaxonomyId>>, sqlx::Error> {
+  let sql = if tag_type == TagType::Tag {
+    r#"SELECT json_agg(json_build_object('id', id, 'slug', slug, 'name', name) ORDER BY id)
+    FROM (
+      SELECT DISTINCT tags.id, tags.slug, tags.name, r.namespace
+      FROM tags INNER JOIN archive_tags r ON r.tag_id = tags.id
+    ) tags
+    GROUP BY LOWER(slug), namespace HAVING COUNT(*) > 1 ORDER BY LOWER(slug)"#
+      .to_string()
+  } else {
+    format!(
+      r#"SELECT json_agg(json_build_object('id', id, 'slug', slug, 'name', name) ORDER BY id)
+      FROM {table} GROUP BY LOWER(slug) HAVING COUNT(*) > 1 ORDER BY LOWER(slug)"#,
+      table = tag_type.table()
+    )
+  };
+
+  let rows: Vec<Json<Vec<TaxonomyId>>> = sqlx::query_scalar(&sql).fetch_all(pool).await?;
+
+  Ok(rows.into_iter().map(|row| row.0).collect())
+}
+
+/// Streams every archive with its relations, one at a time, so memory use doesn't depend on
+/// the size of the library.
+pub fn stream_all_archives(
+  include_deleted: bool,
+  pool: &PgPool,
+) -> impl Stream<Item = Result<ArchiveRelations, DbError>> + '_ {
+  sqlx::query_scalar(r#"SELECT id FROM archives WHERE $1 OR deleted_at IS NULL ORDER BY id ASC"#)
+    .bind(include_deleted)
+    .fetch(pool)
+    .map_err(DbError::from)
+    .and_then(move |id: i64| fetch_archive_data(pool, id))
+}
+
+/// Finds a taxonomy by its exact slug, for resolving URL params. Unlike the search filters, the
+/// slug is compared case-sensitively and without wildcards. A namespace only applies to tags,
+/// which then have to be used in that namespace by some archive.
+pub async fn fetch_taxonomy_by_slug(
+  tag_type: TagType,
+  slug: &str,
+  namespace: Option<&str>,
+  pool: &PgPool,
+) -> Result<Option<TaxonomyId>, sqlx::Error> {
+  let table = tag_type.table();
+
+  match (tag_type, namespace) {
+    (TagType::Tag, Some(namespace)) => {
+      sqlx::query_as(&format!(
+        r#"SELECT {table}.id, {table}.slug, {table}.name FROM {table}
+        WHERE {table}.slug = $1 AND EXISTS (
+          SELECT 1 FROM {relation} r WHERE r.{id} = {table}.id AND r.namespace = $2
+        )"#,
+        relation = tag_type.relation(),
+        id = tag_type.id()
+      ))
+      .bind(slug)
+      .bind(normalize_namespace(namespace))
+      .fetch_optional(pool)
+      .await
+    }
+    _ => {
+      sqlx::query_as(&format!(
+        r#"SELECT {table}.id, {table}.slug, {table}.name FROM {table} WHERE {table}.slug = $1"#
+      ))
+      .bind(slug)
+      .fetch_optional(pool)
+      .await
+    }
+  }
+}
+
+/// Lists a page of every taxonomy of the given type, along with the total amount.
+pub async fn list_taxonomy(
+  tag_type: TagType,
+  page: usize,
+  per_page: usize,
+  sort: TaxonomySort,
+  pool: &PgPool,
+) -> Result<(Vec<TaxonomyId>, i64), sqlx::Error> {
+  let table = tag_type.table();
+
+  let total: i64 = sqlx::query_scalar(&format!(r#"SELECT COUNT(*) FROM {table}"#))
+    .fetch_one(pool)
+    .await?;
+
+  let mut qb = QueryBuilder::new(format!(
+    r#"SELECT {table}.id, {table}.slug, {table}.name FROM {table}"#
+  ));
+
+  match sort {
+    TaxonomySort::Name => {
+      qb.push(format!(r#" ORDER BY {table}.name ASC, {table}.id ASC"#));
+    }
+    TaxonomySort::ArchiveCount => {
+      qb.push(format!(
+        r#" LEFT JOIN ({relation} r INNER JOIN archives ON archives.id = r.archive_id AND archives.deleted_at IS NULL)
+        ON r.{id} = {table}.id
+        GROUP BY {table}.id ORDER BY COUNT(DISTINCT r.archive_id) DESC, {table}.name ASC"#,
+        relation = tag_type.relation(),
+        id = tag_type.id()
+      ));
+    }
+  }
+
+  qb.push(" LIMIT ")
+    .push_bind(per_page as i64)
+    .push(" OFFSET ")
+    .push_bind((per_page * page.saturating_sub(1)) as i64);
+
+  let taxonomies = qb.build_query_as().fetch_all(pool).await?;
+
+  Ok((taxonomies, total))
+}
+
+/// Suggests `keyword:slug` search tokens for a partially typed one, most used first.
+///
+/// A partial with a keyword (`artist:fo`, `a:fo`) only looks into the taxonomies matched by that
+/// keyword and keeps it as typed. Without a keyword, every taxonomy type is suggested under its
+/// full keyword.
+pub async fn suggest_query_tokens(
+  partial: &str,
+  limit: i64,
+  pool: &PgPool,
+) -> Result<Vec<String>, sqlx::Error> {
+  let partial = partial.trim();
+  let (prefix, partial) = match partial.strip_prefix('-') {
+    Some(partial) => ("-", partial),
+    None => ("", partial),
+  };
+
+  let (sources, value) = match partial.split_once(':') {
+    Some((keyword, value)) => {
+      let keyword = keyword.to_lowercase();
+      let sources = match search_filter(&keyword) {
+        Some(SearchFilter::Taxonomy(tag_type)) => vec![(tag_type, None, keyword)],
+        Some(SearchFilter::Creator) => vec![
+          (TagType::Artist, None, keyword.clone()),
+          (TagType::Circle, None, keyword),
+        ],
+        Some(SearchFilter::Tag(namespace)) => vec![(TagType::Tag, Some(namespace), keyword)],
+        _ => vec![],
+      };
+
+      (sources, value)
+    }
+    None => (
+      [
+        (TagType::Artist, "artist"),
+        (TagType::Circle, "circle"),
+        (TagType::Magazine, "magazine"),
+        (TagType::Event, "event"),
+        (TagType::Publisher, "publisher"),
+        (TagType::Parody, "parody"),
+        (TagType::Tag, "tag"),
+      ]
+      .into_iter()
+      .map(|(tag_type, keyword)| (tag_type, None, keyword.to_string()))
+      .collect_vec(),
+      partial,
+    ),
+  };
+
+  if sources.is_empty() || limit < 1 {
+    return Ok(vec![]);
+  }
+
+  let pattern = format!(
+    "{}%",
+    value
+      .trim_matches(['"', '\''])
+      .replace('\\', "\\\\")
+      .replace('%', "\\%")
+      .replace('_', "\\_")
+  );
+
+  let mut qb = QueryBuilder::new("SELECT token FROM (");
+
+  for (i, (tag_type, namespace, keyword)) in sources.iter().enumerate() {
+    if i > 0 {
+      qb.push(" UNION ALL ");
+    }
+
+    let table = tag_type.table();
+
+    qb.push("SELECT ")
+      .push_bind(format!("{prefix}{keyword}:"))
+      .push(format!(
+        r#" || {table}.slug token, COUNT(DISTINCT r.archive_id) count FROM {table}
+        INNER JOIN {relation} r ON r.{id} = {table}.id"#,
+        relation = tag_type.relation(),
+        id = tag_type.id()
+      ));
+
+    if let Some(namespace) = namespace {
+      qb.push(" AND r.namespace ILIKE ")
+        .push_bind(namespace.to_string());
+    }
+
+    qb.push(format!(
+      r#" INNER JOIN archives ON archives.id = r.archive_id AND archives.deleted_at IS NULL
+        WHERE {table}.slug ILIKE "#
+    ))
+    .push_bind(pattern.clone())
+    .push(format!(" OR {table}.name ILIKE "))
+    .push_bind(pattern.clone())
+    .push(format!(" GROUP BY {table}.slug"));
+  }
+
+  qb.push(") suggestions GROUP BY token ORDER BY SUM(count) DESC, token ASC LIMIT ")
+    .push_bind(limit);
+
+  qb.build_query_scalar().fetch_all(pool).await
+}
+
+/// Lists the published archives whose file, or symbolic link when those are enabled, no longer
+/// exists on disk. Nothing is modified.
+pub async fn verify_archive_paths(pool: &PgPool) -> Result<Vec<(ArchiveId, String)>, sqlx::Error> {
+  let mut rows = sqlx::query_as::<_, (i64, String, String)>(
+    r#"SELECT id, slug, path FROM archives WHERE deleted_at IS NULL ORDER BY id ASC"#,
+  )
+  .fetch(pool);
+
+  let mut missing = vec![];
+
+  while let Some((id, slug, path)) = rows.try_next().await? {
+    // A dangling symbolic link doesn't exist either, as the target is checked
+    let exists = Path::new(&path).exists()
+      && (!CONFIG.directories.create_links
+        || CONFIG.directories.links.join(id.to_string()).exists());
+
+    if !exists {
+      missing.push((ArchiveId { id, slug }, path));
+    }
+  }
+
+  Ok(missing)
+}
+
+/// Lists the published archives whose hash isn't a SHA-256 digest, like the placeholders left
+/// by some imports, along with their path.
+pub async fn archives_without_real_hash(
+  pool: &PgPool,
+) -> Result<Vec<(ArchiveId, String)>, sqlx::Error> {
+  let rows = sqlx::query_as::<_, (i64, String, String)>(
+    r#"SELECT id, slug, path FROM archives
+    WHERE deleted_at IS NULL AND hash !~ '^[0-9a-f]{64}$' ORDER BY id ASC"#,
+  )
+  .fetch_all(pool)
+  .await?;
+
+  Ok(
+    rows
+      .into_iter()
+      .map(|(id, slug, path)| (ArchiveId { id, slug }, path))
+      .collect(),
+  )
+}
+
+/// Sets the hash of an archive, unless another archive already has it. Hashes are unique across
+/// deleted archives too. Duplicates are left for the upsert logic to handle instead.
+pub async fn set_archive_hash(id: i64, hash: &str, pool: &PgPool) -> Result<(), DbError> {
+  let hash = hash.trim().to_lowercase();
+
+  if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+    return Err(DbError::Validation(format!(
+      "'{hash}' is not a SHA-256 digest"
+    )));
+  }
+
+  let mut transaction = pool.begin().await?;
+
+  let conflict: Option<i64> =
+    sqlx::query_scalar(r#"SELECT id FROM archives WHERE hash = $1 AND id != $2"#)
+      .bind(&hash)
+      .bind(id)
+      .fetch_optional(&mut *transaction)
+      .await?;
+
+  if let Some(archive_id) = conflict {
+    return Err(DbError::Conflict {
+      constraint: "archives_hash_key".to_string(),
+      archive_id: Some(archive_id),
+    });
+  }
+
+  let affected = sqlx::query(r#"UPDATE archives SET hash = $2, updated_at = NOW() WHERE id = $1"#)
+    .bind(id)
+    .bind(&hash)
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+
+  if affected == 0 {
+    return Err(DbError::NotFound);
+  }
+
+  transaction.commit().await?;
+
+  Ok(())
+}
+
+/// Compares two archives, to decide which one to keep when merging duplicates.
+pub async fn diff_archives(a: i64, b: i64, pool: &PgPool) -> Result<ArchiveDiff, sqlx::Error> {
+  let ids = [a, b];
+
+  let rows: Vec<(i64, String, Option<i16>, i64)> =
+    sqlx::query_as(r#"SELECT id, title, pages, size FROM archives WHERE id = ANY($1)"#)
+      .bind(&ids[..])
+      .fetch_all(pool)
+      .await?;
+
+  let find = |id: i64| {
+    rows
+      .iter()
+      .find(|row| row.0 == id)
+      .cloned()
+      .ok_or(sqlx::Error::RowNotFound)
+  };
+  let (a_row, b_row) = (find(a)?, find(b)?);
+
+  let sources: Vec<(i64, String, Option<String>)> = sqlx::query_as(
+    r#"SELECT archive_id, name, url FROM archive_sources WHERE archive_id = ANY($1) ORDER BY name ASC"#,
+  )
+  .bind(&ids[..])
+  .fetch_all(pool)
+  .await?;
+
+  let sources_of = |id: i64| {
+    sources
+      .iter()
+      .filter(|source| source.0 == id)
+      .map(|(_, name, url)| url.clone().unwrap_or(name.clone()))
+      .collect_vec()
+  };
+
+  let mut relations = fetch_relations_for_ids(&ids, SearchProjection::Full, pool).await?;
+  let a_relations = relations.remove(&a).unwrap_or_default();
+  let b_relations = relations.remove(&b).unwrap_or_default();
+
+  let taxonomy = |a: Vec<api::models::Taxonomy>, b: Vec<api::models::Taxonomy>| {
+    SetDiff::new(
+      a.into_iter().map(|t| t.slug).collect(),
+      b.into_iter().map(|t| t.slug).collect(),
+    )
+  };
+
+  Ok(ArchiveDiff {
+    a,
+    b,
+    title: FieldDiff::compare(a_row.1, b_row.1),
+    pages: FieldDiff::compare(a_row.2, b_row.2),
+    size: FieldDiff::compare(a_row.3, b_row.3),
+    artists: taxonomy(a_relations.artists, b_relations.artists),
+    circles: taxonomy(a_relations.circles, b_relations.circles),
+    magazines: taxonomy(a_relations.magazines, b_relations.magazines),
+    events: taxonomy(a_relations.events, b_relations.events),
+    publishers: taxonomy(a_relations.publishers, b_relations.publishers),
+    parodies: taxonomy(a_relations.parodies, b_relations.parodies),
+    tags: taxonomy(a_relations.tags, b_relations.tags),
+    sources: SetDiff::new(sources_of(a), sources_of(b)),
+  })
+}
+
+/// Generates the slug again from the current title. Returns the old and the new slug, so
+/// redirects can be set up.
+///
+/// Soft-deleted archives are updated too. Symbolic links are named after the ID, so they don't
+/// change.
+pub async fn regenerate_slug(id: i64, pool: &PgPool) -> anyhow::Result<(String, String)> {
+  let mut transaction = pool.begin().await?;
+
+  let (old_slug, title): (String, String) =
+    sqlx::query_as(r#"SELECT slug, title FROM archives WHERE id = $1 FOR UPDATE"#)
+      .bind(id)
+      .fetch_optional(&mut *transaction)
+      .await?
+      .ok_or_else(|| anyhow!("Archive ID {id} not found"))?;
+
+  let slug = slugify(&title);
+
+  if slug.is_empty() {
+    return Err(anyhow!(
+      "Couldn't generate a slug for archive ID {id} from the title '{title}'. Keeping '{old_slug}'"
+    ));
+  }
+
+  let slug = unique_slug(&slug, Some(id), &mut transaction).await?;
+
+  sqlx::query(r#"UPDATE archives SET slug = $2, updated_at = NOW() WHERE id = $1"#)
+    .bind(id)
+    .bind(&slug)
+    .execute(&mut *transaction)
+    .await?;
+
+  transaction.commit().await?;
+
+  Ok((old_slug, slug))
+}
+
+/// Collapses the sources of an archive whose names only differ in case or surrounding spaces.
+/// The one with an URL is kept. Returns the amount of removed sources.
+pub async fn collapse_source_duplicates(pool: &PgPool) -> Result<u64, sqlx::Error> {
+  let mut transaction = pool.begin().await?;
+
+  let affected = sqlx::query!(
+    r#"DELETE FROM archive_sources USING (
+      SELECT archive_id, name, ROW_NUMBER() OVER (
+        PARTITION BY archive_id, LOWER(TRIM(name)) ORDER BY url IS NULL, name
+      ) AS position FROM archive_sources
+    ) duplicates
+    WHERE archive_sources.archive_id = duplicates.archive_id
+    AND archive_sources.name = duplicates.name AND duplicates.position > 1"#
+  )
+  .execute(&mut *transaction)
+  .await?
+  .rows_affected();
+
+  sqlx::query!(r#"UPDATE archive_sources SET name = TRIM(name) WHERE name <> TRIM(name)"#)
+    .execute(&mut *transaction)
+    .await?;
+
+  transaction.commit().await?;
+
+  Ok(affected)
+}
+
+/// Archives right before and after the given one in the search results, following the same
+/// filters and order. `None` at either end, or when the archive isn't in the results.
+pub async fn archive_neighbors(
+  id: i64,
+  query: &SearchQuery,
+  pool: &PgPool,
+) -> Result<(Option<ArchiveId>, Option<ArchiveId>), sqlx::Error> {
+  let (parsed, phrases) = parse_search_text(&query.value, query.match_mode);
+
+  let mut qb = QueryBuilder::new(
+    r#"SELECT prev_id, prev_slug, next_id, next_slug FROM (SELECT archives.id,
+    LAG(archives.id) OVER neighbors AS prev_id, LAG(archives.slug) OVER neighbors AS prev_slug,
+    LEAD(archives.id) OVER neighbors AS next_id, LEAD(archives.slug) OVER neighbors AS next_slug"#,
+  );
+
+  push_search_filters(&mut qb, query, &parsed, &phrases);
+  push_search_grouping(&mut qb, query);
+
+  qb.push(" WINDOW neighbors AS (");
+  push_search_order(&mut qb, query, &parsed);
+  qb.push(")) results WHERE id = ").push_bind(id);
+
+  let Some(row) = qb
+    .build_query_as::<Neighbors>()
+    .fetch_optional(pool)
+    .await?
+  else {
+    return Ok((None, None));
+  };
+
+  let archive_id = |id: Option<i64>, slug: Option<String>| match (id, slug) {
+    (Some(id), Some(slug)) => Some(ArchiveId { id, slug }),
+    _ => None,
+  };
+
+  Ok((
+    archive_id(row.prev_id, row.prev_slug),
+    archive_id(row.next_id, row.next_slug),
+  ))
+}
+
+/// Picks one archive from the search results, the first one of the random sort with the same
+/// seed. Only that archive is fetched instead of shuffling every result.
+pub async fn random_archive(
+  seed: &str,
+  filters: &SearchQuery,
+  pool: &PgPool,
+) -> Result<Option<ArchiveId>, sqlx::Error> {
+  let (parsed, phrases) = parse_search_text(&filters.value, filters.match_mode);
+
+  let mut qb = QueryBuilder::new(r#"SELECT archives.id, archives.slug"#);
+
+  push_search_filters(&mut qb, filters, &parsed, &phrases);
+  push_search_grouping(&mut qb, filters);
+
+  qb.push(" ORDER BY ");
+  push_random_order(&mut qb, seed);
+  qb.push(", archives.id ASC LIMIT 1");
+
+  qb.build_query_as().fetch_optional(pool).await
+}
+
+/// Sets the page count of the published archives that don't match their images, clamping the
+/// covers to the last page. Returns the amount of corrected archives.
+pub async fn recompute_page_counts(pool: &PgPool) -> Result<u64, sqlx::Error> {
+  let affected = sqlx::query(
+    r#"UPDATE archives SET pages = counts.pages,
+    thumbnail = LEAST(archives.thumbnail, GREATEST(counts.pages, 1)),
+    alt_thumbnail = CASE WHEN archives.alt_thumbnail IS NULL THEN NULL
+      ELSE LEAST(archives.alt_thumbnail, GREATEST(counts.pages, 1)) END,
+    updated_at = NOW()
+    FROM (
+      SELECT archives.id, COUNT(archive_images.archive_id)::smallint pages FROM archives
+      LEFT JOIN archive_images ON archive_images.archive_id = archives.id
+      WHERE archives.deleted_at IS NULL GROUP BY archives.id
+    ) counts
+    WHERE archives.id = counts.id AND archives.pages IS DISTINCT FROM counts.pages"#,
+  )
+  .execute(pool)
+  .await?
+  .rows_affected();
+
+  Ok(affected)
+}
+
+/// Finds the archives with a source pointing to the given URL. Besides exact matches, URLs are
+/// compared in their normalized form, ignoring the scheme, `www.` and trailing slashes.
+pub async fn find_archives_by_source_url(
+  url: &str,
+  include_deleted: bool,
+  pool: &PgPool,
+) -> Result<Vec<ArchiveId>, sqlx::Error> {
+  let normalized = utils::normalize_source_url(url);
+
+  if normalized.is_empty() {
+    return Ok(vec![]);
+  }
+
+  let mut candidates = vec![url.trim().to_string()];
+
+  for scheme in ["", "http://", "https://"] {
+    for www in ["", "www."] {
+      for slash in ["", "/"] {
+        candidates.push(format!("{scheme}{www}{normalized}{slash}"));
+      }
+    }
+  }
+
+  sqlx::query_as(
+    r#"SELECT DISTINCT archives.id, archives.slug FROM archive_sources
+    INNER JOIN archives ON archives.id = archive_sources.archive_id
+    WHERE archive_sources.url = ANY($1) AND ($2 OR archives.deleted_at IS NULL)
+    ORDER BY archives.id ASC"#,
+  )
+  .bind(candidates)
+  .bind(include_deleted)
+  .fetch_all(pool)
+  .await
+}
+
+/// Adds the given `(name, namespace)` tags to every archive, creating the missing tags. Tags the
+/// archives already have are kept, as is any other tag.
+pub async fn bulk_add_tags(
+  archive_ids: &[i64],
+  tags: Vec<(String, String)>,
+  pool: &PgPool,
+) -> anyhow::Result<()> {
+  if archive_ids.is_empty() || tags.is_empty() {
+    return Ok(());
+  }
+
+  let (tags, disallowed) = filter_tag_namespaces(tags, &CONFIG.metadata);
+
+  if !disallowed.is_empty() {
+    warn!(target: "db::bulk_add_tags", "{}", disallowed_tags_message(&disallowed));
+  }
+
+  let mut transaction = pool.begin().await?;
+
+  let tags = insert_tags(tags, &mut transaction).await?;
+
+  sqlx::query(
+    r#"INSERT INTO archive_tags (archive_id, tag_id, namespace)
+    SELECT archive_id, tag_id, namespace FROM UNNEST($1::bigint[]) archive_id
+    CROSS JOIN UNNEST($2::bigint[], $3::text[]) AS t (tag_id, namespace)
+    ON CONFLICT DO NOTHING"#,
+  )
+  .bind(archive_ids)
+  .bind(tags.iter().map(|(_, id)| *id).collect_vec())
+  .bind(
+    tags
+      .iter()
+      .map(|(tag, _)| tag.namespace.clone())
+      .collect_vec(),
+  )
+  .execute(&mut *transaction)
+  .await?;
+
+  touch_archives(archive_ids, &mut *transaction).await?;
+
+  transaction.commit().await?;
+
+  Ok(())
+}
+
+/// Removes the given `(name, namespace)` tags from every archive. Other tags are kept.
+pub async fn bulk_remove_tags(
+  archive_ids: &[i64],
+  tags: Vec<(String, String)>,
+  pool: &PgPool,
+) -> anyhow::Result<()> {
+  if archive_ids.is_empty() || tags.is_empty() {
+    return Ok(());
+  }
+
+  let (slugs, namespaces): (Vec<String>, Vec<String>) = tags
+    .iter()
+    .map(|(name, namespace)| (slugify(name), normalize_namespace(namespace)))
+    .unzip();
+
+  let mut transaction = pool.begin().await?;
+
+  sqlx::query(
+    r#"DELETE FROM archive_tags USING tags
+    WHERE tags.id = archive_tags.tag_id AND archive_tags.archive_id = ANY($1)
+    AND (tags.slug, archive_tags.namespace) IN (SELECT * FROM UNNEST($2::text[], $3::text[]))"#,
+  )
+  .bind(archive_ids)
+  .bind(slugs)
+  .bind(namespaces)
+  .execute(&mut *transaction)
+  .await?;
+
+  touch_archives(archive_ids, &mut *transaction).await?;
+
+  transaction.commit().await?;
+
+  Ok(())
+}
+
+/// Creates a copy of an archive and all of its relations for another file, like an edited
+/// version of it. The original is kept as is, and the copy gets its own slug. Returns the ID of
+/// the copy.
+pub async fn duplicate_archive(
+  id: i64,
+  new_hash: String,
+  new_path: String,
+  pool: &PgPool,
+) -> anyhow::Result<i64> {
+  let mut transaction = pool.begin().await?;
+
+  let (hash, slug): (String, String) =
+    sqlx::query_as(r#"SELECT hash, slug FROM archives WHERE id = $1"#)
+      .bind(id)
+      .fetch_optional(&mut *transaction)
+      .await?
+      .ok_or(DbError::NotFound)?;
+
+  let new_id = copy_archive(hash, new_hash, &mut transaction).await?;
+  let slug = unique_slug(&slug, Some(new_id), &mut transaction).await?;
+
+  sqlx::query(r#"UPDATE archives SET slug = $2, path = $3 WHERE id = $1"#)
+    .bind(new_id)
+    .bind(slug)
+    .bind(&new_path)
+    .execute(&mut *transaction)
+    .await?;
+
+  copy_relations(id, new_id, &mut transaction).await?;
+
+  transaction.commit().await?;
+
+  link_archive(new_id, &new_path, &MultiProgress::new());
+
+  Ok(new_id)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::routes::{Ordering, Sorting};
+  use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+  static NEXT_ARCHIVE: AtomicUsize = AtomicUsize::new(1);
+
+  /// Data for a new archive, with a unique path and hash.
+  fn archive(title: &str) -> UpsertArchiveData {
+    let n = NEXT_ARCHIVE.fetch_add(1, AtomicOrdering::Relaxed);
+
+    UpsertArchiveData {
+      title: Some(title.to_string()),
+      path: Some(format!("/content/{n}.zip")),
+      hash: Some(format!("{n:0>64}")),
+      pages: Some(10),
+      size: Some(1024),
+      thumbnail: Some(1),
+      released_at: Some(NaiveDateTime::default()),
+      ..Default::default()
+    }
+  }
+
+  async fn insert(data: UpsertArchiveData, pool: &PgPool) -> i64 {
+    upsert_archive(data, pool, &MultiProgress::new())
+      .await
+      .unwrap()
+      .id
+  }
+
+  fn search_query(value: &str) -> SearchQuery {
+    SearchQuery {
+      value: value.to_string(),
+      page: 1,
+      sort: Sorting::CreatedAt,
+      order: Ordering::Asc,
+      favorited_by: None,
+      explain: false,
+      include_deleted: false,
+      only_deleted: false,
+      seed: None,
+      facets: false,
+      blacklist: vec![],
+      match_mode: MatchMode::All,
+      projection: SearchProjection::Full,
+    }
+  }
+
+  async fn search_ids(value: &str, pool: &PgPool) -> Vec<i64> {
+    search(&search_query(value), pool)
+      .await
+      .unwrap()
+      .archives
+      .into_iter()
+      .map(|archive| archive.id)
+      .sorted()
+      .collect()
+  }
+
+  fn tag_matches_sql(value: &str) -> String {
+    let mut qb = QueryBuilder::new("");
+    add_tag_matches(&mut qb, value);
+    qb.sql().to_string()
+  }
+
+  /// SQL of a taxonomy filter matching its name and slug, without the opening ` AND (`.
+  fn taxonomy_sql(tag_type: TagType, bind: usize) -> String {
+    let exists = |column: &str, bind: usize| {
+      format!(
+        "EXISTS (\n          SELECT 1 FROM {relation} LEFT JOIN {table} ON {table}.id = {relation}.{id} WHERE {relation}.archive_id = archives.id AND {table}.{column} ILIKE ${bind}\n        )",
+        relation = tag_type.relation(),
+        table = tag_type.table(),
+        id = tag_type.id(),
+      )
+    };
+
+    format!(
+      "  (\n    (\n      (\n        {} OR\n        {}\n      )\n    ))",
+      exists("name", bind),
+      exists("slug", bind + 1)
+    )
+  }
+
+  fn assert_balanced(sql: &str) {
+    assert_eq!(sql.matches('(').count(), sql.matches(')').count(), "{sql}");
+  }
+
+  #[test]
+  fn grouped_filters_are_ored() {
+    let sql = tag_matches_sql("{artist:a circle:b}");
+
+    assert_eq!(
+      sql,
+      format!(
+        " AND (({}) OR ({}))",
+        taxonomy_sql(TagType::Artist, 1),
+        taxonomy_sql(TagType::Circle, 3)
+      )
+    );
+    assert_balanced(&sql);
+  }
+
+  #[test]
+  fn group_is_anded_with_ungrouped_filters() {
+    let group = format!(
+      " AND (({}) OR ({}))",
+      taxonomy_sql(TagType::Artist, 1),
+      taxonomy_sql(TagType::Circle, 3)
+    );
+
+    let sql = tag_matches_sql("{artist:a circle:b} magazine:c");
+    assert_eq!(
+      sql,
+      format!("{group} AND ({})", taxonomy_sql(TagType::Magazine, 5))
+    );
+    assert_balanced(&sql);
+
+    let sql = tag_matches_sql("magazine:c {artist:a circle:b} parody:d");
+    assert_balanced(&sql);
+    assert!(sql.starts_with(&format!(
+      " AND ({}) AND ((",
+      taxonomy_sql(TagType::Magazine, 1)
+    )));
+    assert!(sql.ends_with(&format!(")) AND ({})", taxonomy_sql(TagType::Parody, 7))));
+  }
+
+  #[test]
+  fn keyword_aliases_produce_the_same_sql() {
+    for (alias, keyword) in [
+      ("a", "artist"),
+      ("c", "circle"),
+      ("m", "magazine"),
+      ("p", "parody"),
+      ("lang", "language"),
+    ] {
+      assert_eq!(
+        tag_matches_sql(&format!("{alias}:foo -{alias}:bar")),
+        tag_matches_sql(&format!("{keyword}:foo -{keyword}:bar")),
+        "{alias}: and {keyword}: differ"
+      );
+    }
+
+    assert_eq!(
+      tag_matches_sql("a:foo"),
+      format!(" AND ({})", taxonomy_sql(TagType::Artist, 1))
+    );
+  }
+
+  #[test]
+  fn title_is_matched_as_a_single_pattern() {
+    assert_eq!(
+      tag_matches_sql(r#"title:"Tom & Jerry (Remake) | 2""#),
+      " AND ((archives.title ILIKE $1))"
+    );
+    assert_eq!(
+      tag_matches_sql("-title:foo*"),
+      " AND ((archives.title IS NULL OR archives.title NOT ILIKE $1))"
+    );
+  }
+
+  #[test]
+  fn like_pattern_escapes_wildcards() {
+    assert_eq!(like_pattern("100% Tom_*"), r"100\% Tom\_%");
+    assert_eq!(like_pattern(r"a\b"), r"a\\b");
+  }
+
+  #[sqlx::test]
+  async fn event_filter_restricts_results(pool: PgPool) {
+    let comiket = insert(
+      UpsertArchiveData {
+        events: Some(vec!["Comiket 100".to_string()]),
+        ..archive("First")
+      },
+      &pool,
+    )
+    .await;
+    let other = insert(
+      UpsertArchiveData {
+        events: Some(vec!["Reitaisai 20".to_string()]),
+        ..archive("Second")
+      },
+      &pool,
+    )
+    .await;
+    insert(archive("Third"), &pool).await;
+
+    assert_eq!(search_ids("event:comiket-100", &pool).await, vec![comiket]);
+    assert_eq!(
+      search_ids("event:\"Reitaisai 20\"", &pool).await,
+      vec![other]
+    );
+    assert_eq!(search_ids("reitaisai", &pool).await, vec![other]);
+  }
+
+  #[sqlx::test]
+  async fn same_titles_get_distinct_slugs(pool: PgPool) {
+    let mp = MultiProgress::new();
+    let first = upsert_archive(archive("Same Title"), &pool, &mp)
+      .await
+      .unwrap();
+    let second = upsert_archive(archive("Same Title"), &pool, &mp)
+      .await
+      .unwrap();
+
+    assert_eq!(first.slug, "same-title");
+    assert_eq!(second.slug, "same-title-2");
+  }
+
+  #[sqlx::test]
+  async fn language_filter_matches_any_listed_language(pool: PgPool) {
+    let language = |title: &str, language: Option<&str>| UpsertArchiveData {
+      language: language.map(|language| language.to_string()),
+      ..archive(title)
+    };
+
+    let japanese = insert(language("First", Some("Japanese")), &pool).await;
+    let english = insert(language("Second", Some("english")), &pool).await;
+    let chinese = insert(language("Third", Some("Chinese")), &pool).await;
+    let none = insert(language("Fourth", None), &pool).await;
+
+    assert_eq!(
+      search_ids("language:japanese|english", &pool).await,
+      vec![japanese, english]
+    );
+    assert_eq!(
+      search_ids("-language:japanese", &pool).await,
+      vec![english, chinese, none]
+    );
+    assert_eq!(
+      search_ids("language:none|english", &pool).await,
+      vec![english, none]
+    );
+    assert_eq!(
+      search_ids("-language:none|english", &pool).await,
+      vec![japanese, chinese]
+    );
+  }
+
+  #[sqlx::test]
+  async fn preview_matches_upsert(pool: PgPool) {
+    let data = archive("Preview");
+
+    assert_eq!(
+      preview_upsert_archive(&data, &pool).await.unwrap(),
+      UpsertOutcome::Insert
+    );
+    assert!(matches!(
+      preview_upsert_archive(&UpsertArchiveData::default(), &pool).await,
+      Err(DbError::Validation(_))
+    ));
+
+    let id = insert(data.clone(), &pool).await;
+
+    assert_eq!(
+      preview_upsert_archive(&data, &pool).await.unwrap(),
+      UpsertOutcome::Update(id)
+    );
+
+    let replaced = UpsertArchiveData {
+      hash: Some("f".repeat(64)),
+      ..data
+    };
+
+    assert_eq!(
+      preview_upsert_archive(&replaced, &pool).await.unwrap(),
+      UpsertOutcome::ReplaceCopy { old_id: id }
+    );
+
+    let new_id = insert(replaced, &pool).await;
+    assert_ne!(new_id, id);
+  }
+
+  #[sqlx::test]
+  async fn missing_filter_matches_archives_without_the_relation(pool: PgPool) {
+    let with_artist = insert(
+      UpsertArchiveData {
+        artists: Some(vec!["Artist".to_string()]),
+        tags: Some(vec![("Tag".to_string(), "female".to_string())]),
+        ..archive("First")
+      },
+      &pool,
+    )
+    .await;
+    let without_artist = insert(
+      UpsertArchiveData {
+        tags: Some(vec![("Tag".to_string(), "male".to_string())]),
+        ..archive("Second")
+      },
+      &pool,
+    )
+    .await;
+
+    assert_eq!(search_ids("no:artist", &pool).await, vec![without_artist]);
+    assert_eq!(search_ids("no:a", &pool).await, vec![without_artist]);
+    assert_eq!(search_ids("-no:artist", &pool).await, vec![with_artist]);
+    assert_eq!(search_ids("no:female", &pool).await, vec![without_artist]);
+    assert_eq!(search_ids("no:male", &pool).await, vec![with_artist]);
+    assert_eq!(search_ids("no:tag", &pool).await, Vec::<i64>::new());
+  }
+
+  #[sqlx::test]
+  async fn facets_count_tags_per_namespace(pool: PgPool) {
+    for (title, namespace) in [("First", "female"), ("Second", "female"), ("Third", "male")] {
+      insert(
+        UpsertArchiveData {
+          artists: Some(vec!["Artist".to_string()]),
+          tags: Some(vec![("Foo".to_string(), namespace.to_string())]),
+          ..archive(title)
+        },
+        &pool,
+      )
+      .await;
+    }
+
+    let facets = taxonomy_facets(&search_query(""), &pool).await.unwrap();
+
+    let artists = &facets[&TagType::Artist];
+    assert_eq!(artists.len(), 1);
+    assert_eq!(artists[0].taxonomy.slug, "artist");
+    assert_eq!(artists[0].namespace, None);
+    assert_eq!(artists[0].count, 3);
+
+    let tags = facets[&TagType::Tag]
+      .iter()
+      .map(|facet| (facet.namespace.as_deref(), facet.count))
+      .collect::<Vec<_>>();
+    assert_eq!(tags, vec![(Some("female"), 2), (Some("male"), 1)]);
+    assert_eq!(
+      facets[&TagType::Tag][0].taxonomy.id,
+      facets[&TagType::Tag][1].taxonomy.id
+    );
+  }
+
+  #[sqlx::test]
+  async fn bracketed_title_round_trips_through_search(pool: PgPool) {
+    let bracketed = insert(archive("(C99) [Circle] Title"), &pool).await;
+    insert(archive("Title"), &pool).await;
+
+    assert_eq!(
+      search_ids(r#""(C99) [Circle] Title""#, &pool).await,
+      vec![bracketed]
+    );
+    assert_eq!(
+      search_ids("(C99) [Circle] Title", &pool).await,
+      vec![bracketed]
+    );
+  }
+
+  #[sqlx::test]
+  async fn tied_results_are_paginated_once(pool: PgPool) {
+    let mut ids = vec![];
+
+    for _ in 0..50 {
+      ids.push(insert(archive("Tied"), &pool).await);
+    }
+
+    sqlx::query("UPDATE archives SET created_at = '2020-01-01'")
+      .execute(&pool)
+      .await
+      .unwrap();
+
+    for (value, sort) in [
+      ("", Sorting::CreatedAt),
+      ("", Sorting::ReleasedAt),
+      ("", Sorting::Title),
+      ("", Sorting::Pages),
+      ("tied", Sorting::Relevance),
+    ] {
+      for desc in [false, true] {
+        let mut seen = vec![];
+
+        for page in 1..=3 {
+          let query = SearchQuery {
+            page,
+            sort,
+            order: if desc { Ordering::Desc } else { Ordering::Asc },
+            ..search_query(value)
+          };
+
+          let results = search(&query, &pool).await.unwrap();
+          seen.extend(results.archives.into_iter().map(|archive| archive.id));
+        }
+
+        seen.sort();
+        assert_eq!(seen, ids, "{sort:?}, descending: {desc}");
+      }
+    }
+  }
+
+  fn blacklist_sql(blacklist: &[&str]) -> String {
+    let mut qb = QueryBuilder::new("");
+    add_blacklist(
+      &mut qb,
+      &blacklist
+        .iter()
+        .map(|entry| entry.to_string())
+        .collect_vec(),
+    );
+    qb.sql().to_string()
+  }
+
+  #[test]
+  fn blacklist_skips_unknown_keywords() {
+    assert_eq!(blacklist_sql(&["foo:bar", "guro", "x:y"]), "");
+
+    let tag = " AND NOT EXISTS (SELECT 1 FROM archive_tags INNER JOIN tags ON tags.id = archive_tags.tag_id WHERE archive_tags.archive_id = archives.id AND archive_tags.namespace ILIKE $1 AND tags.slug = $2)";
+
+    assert_eq!(blacklist_sql(&["foo:bar", "female:guro"]), tag);
+    assert_eq!(blacklist_sql(&["tag:guro", "unknown:guro"]), tag);
+    assert_eq!(
+      blacklist_sql(&["a:foo"]),
+      " AND NOT EXISTS (SELECT 1 FROM archive_artists INNER JOIN artists ON artists.id = archive_artists.artist_id WHERE archive_artists.archive_id = archives.id AND artists.slug = $1)"
+    );
+  }
+
+  #[test]
+  fn parse_query_joins_words_by_mode() {
+    assert_eq!(
+      parse_query("foo bar baz", MatchMode::All),
+      "foo:*&bar:*&baz:*"
+    );
+    assert_eq!(
+      parse_query("foo bar baz", MatchMode::Any),
+      "foo:*|bar:*|baz:*"
+    );
+  }
+
+  #[test]
+  fn parse_query_explicit_operators_override_mode() {
+    assert_eq!(
+      parse_query("foo | bar baz", MatchMode::All),
+      "foo:*|bar:*&baz:*"
+    );
+    assert_eq!(
+      parse_query("foo bar & baz", MatchMode::Any),
+      "foo:*|bar:*&baz:*"
+    );
+    assert_eq!(
+      parse_query("foo|bar baz", MatchMode::All),
+      "(foo:*|bar:*)&baz:*"
+    );
+    assert_eq!(
+      parse_query("foo&bar baz", MatchMode::Any),
+      "(foo:*&bar:*)|baz:*"
+    );
+  }
+
+  #[test]
+  fn parse_query_always_excludes_negated_words() {
+    assert_eq!(
+      parse_query("foo -bar baz", MatchMode::All),
+      "(foo:*&baz:*)&!bar:*"
+    );
+    assert_eq!(
+      parse_query("foo -bar baz", MatchMode::Any),
+      "(foo:*|baz:*)&!bar:*"
+    );
+    assert_eq!(parse_query("-foo", MatchMode::Any), "!foo:*");
+    assert_eq!(parse_query("foo bar$", MatchMode::All), "foo:*&bar");
+  }
+
+  #[test]
+  fn parse_query_term_handles_prefixes() {
+    assert_eq!(
+      parse_query_term("artist:foo"),
+      Some(("foo:*".to_string(), false))
+    );
+    assert_eq!(parse_query_term("-foo$"), Some(("!foo".to_string(), true)));
+    assert_eq!(parse_query_term("-"), None);
+  }
+
+  #[sqlx::test]
+  async fn taxonomy_counts_exclude_deleted_archives(pool: PgPool) {
+    let with_artist = |title: &str| UpsertArchiveData {
+      artists: Some(vec!["Artist".to_string()]),
+      tags: Some(vec![("Tag".to_string(), "female".to_string())]),
+      ..archive(title)
+    };
+
+    insert(with_artist("First"), &pool).await;
+    let deleted = insert(with_artist("Second"), &pool).await;
+    soft_delete_archives(&[deleted], &pool).await.unwrap();
+
+    let artist_id: i64 = sqlx::query_scalar("SELECT id FROM artists WHERE slug = 'artist'")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+    let tag_id: i64 = sqlx::query_scalar("SELECT id FROM tags WHERE slug = 'tag'")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+
+    assert_eq!(
+      taxonomy_archive_count(TagType::Artist, artist_id, None, &pool)
+        .await
+        .unwrap(),
+      1
+    );
+    assert_eq!(
+      taxonomy_archive_count(TagType::Tag, tag_id, Some("female"), &pool)
+        .await
+        .unwrap(),
+      1
+    );
+
+    let facets = taxonomy_facets(&search_query(""), &pool).await.unwrap();
+    assert_eq!(facets[&TagType::Artist][0].count, 1);
+    assert_eq!(facets[&TagType::Tag][0].count, 1);
+
+    let stats = library_stats(&pool).await.unwrap();
+    assert_eq!(stats.archives, 1);
+
+    let suggested = suggest_tags(&[], 10, &pool).await.unwrap();
+    assert_eq!(suggested.len(), 1);
+    assert_eq!(suggested[0].1, 1);
+  }
+
+  #[sqlx::test]
+  async fn source_names_differing_in_case_are_one_source(pool: PgPool) {
+    let source = |name: &str, url: Option<&str>| ArchiveSource {
+      name: name.to_string(),
+      url: url.map(|url| url.to_string()),
+    };
+    let sources = |archive_id: i64| {
+      sqlx::query_as::<_, ArchiveSource>(
+        "SELECT name, url FROM archive_sources WHERE archive_id = $1",
+      )
+      .bind(archive_id)
+      .fetch_all(&pool)
+    };
+
+    let data = archive("Sources");
+    let id = insert(
+      UpsertArchiveData {
+        sources: Some(vec![source("E-Hentai", None)]),
+        ..data.clone()
+      },
+      &pool,
+    )
+    .await;
+    insert(
+      UpsertArchiveData {
+        sources: Some(vec![source("e-hentai", Some("https://e-hentai.org/g/1"))]),
+        ..data
+      },
+      &pool,
+    )
+    .await;
+
+    let rows = sources(id).await.unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].name, "E-Hentai");
+    assert_eq!(rows[0].url.as_deref(), Some("https://e-hentai.org/g/1"));
+
+    let other = insert(archive("Duplicated sources"), &pool).await;
+    sqlx::query(
+      "INSERT INTO archive_sources (archive_id, name, url) VALUES ($1, 'E-Hentai', NULL), ($1, 'e-hentai', 'https://e-hentai.org/g/2')",
+    )
+    .bind(other)
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    assert_eq!(collapse_source_duplicates(&pool).await.unwrap(), 1);
+
+    let rows = sources(other).await.unwrap();
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0].url.as_deref(), Some("https://e-hentai.org/g/2"));
+  }
+
+  fn missing_sql(value: &str, negate: bool) -> String {
+    let mut qb = QueryBuilder::new("");
+    push_missing_sql(&mut qb, value, negate);
+    qb.sql().to_string()
+  }
+
+  #[test]
+  fn missing_sql_handles_sources() {
+    let sources =
+      "NOT EXISTS (SELECT 1 FROM archive_sources WHERE archive_sources.archive_id = archives.id)";
+
+    assert_eq!(missing_sql("source", false), format!("(({sources})"));
+    assert_eq!(missing_sql("Sources", false), format!("(({sources})"));
+    assert_eq!(missing_sql("source", true), format!("(NOT ({sources})"));
+    assert_eq!(
+      missing_sql("artist", false),
+      "((NOT EXISTS (SELECT 1 FROM archive_artists WHERE archive_artists.archive_id = archives.id))"
+    );
+    assert_eq!(
+      missing_sql("female", true),
+      "(NOT (NOT EXISTS (SELECT 1 FROM archive_tags WHERE archive_tags.archive_id = archives.id AND archive_tags.namespace ILIKE $1))"
+    );
+    assert_eq!(missing_sql("unknown", false), "(TRUE");
+  }
+
+  #[sqlx::test]
+  async fn source_filters_match_archives_with_and_without_sources(pool: PgPool) {
+    let with_source = insert(
+      UpsertArchiveData {
+        sources: Some(vec![ArchiveSource {
+          name: "E-Hentai".to_string(),
+          url: None,
+        }]),
+        ..archive("First")
+      },
+      &pool,
+    )
+    .await;
+    let without_source = insert(archive("Second"), &pool).await;
+
+    assert_eq!(search_ids("no:source", &pool).await, vec![without_source]);
+    assert_eq!(search_ids("has:source", &pool).await, vec![with_source]);
+    assert_eq!(search_ids("-no:source", &pool).await, vec![with_source]);
+    assert_eq!(
+      search_ids("has:sources first", &pool).await,
+      vec![with_source]
+    );
+    assert_eq!(
+      search_ids("has:source second", &pool).await,
+      Vec::<i64>::new()
+    );
+  }
+
+  #[sqlx::test]
+  async fn search_stops_after_counting_without_a_page_of_results(pool: PgPool) {
+    for title in ["First", "Second", "Third"] {
+      insert(archive(title), &pool).await;
+    }
+
+    // Ordering by artist and listing the results read the artists, while counting doesn't, so
+    // the search only succeeds if it stops after counting
+    sqlx::query("ALTER TABLE artists RENAME TO hidden_artists")
+      .execute(&pool)
+      .await
+      .unwrap();
+
+    let query = SearchQuery {
+      sort: Sorting::Artist,
+      ..search_query("nothing")
+    };
+    let results = search(&query, &pool).await.unwrap();
+    assert_eq!(results.total, 0);
+    assert!(results.archives.is_empty());
+
+    let query = SearchQuery {
+      page: 2,
+      sort: Sorting::Artist,
+      ..search_query("")
+    };
+    let results = search(&query, &pool).await.unwrap();
+    assert_eq!(results.total, 3);
+    assert!(results.archives.is_empty());
+
+    let query = SearchQuery {
+      sort: Sorting::Artist,
+      ..search_query("")
+    };
+    assert!(search(&query, &pool).await.is_err());
+  }
+
+  fn pages_sql(value: &str, negate: bool) -> String {
+    let mut qb = QueryBuilder::new("");
+    push_pages_sql(&mut qb, value, negate);
+    qb.sql().to_string()
+  }
+
+  #[test]
+  fn pages_filter_sql() {
+    assert_eq!(
+      pages_sql("20", false),
+      "(COALESCE((archives.pages = $1), FALSE)"
+    );
+    assert_eq!(
+      pages_sql("10-20", false),
+      "(COALESCE(((archives.pages >= COALESCE($1, archives.pages) AND archives.pages <= COALESCE($2, archives.pages))), FALSE)"
+    );
+    assert_eq!(
+      pages_sql("-20", true),
+      "(NOT COALESCE(((archives.pages >= COALESCE($1, archives.pages) AND archives.pages <= COALESCE($2, archives.pages))), FALSE)"
+    );
+    assert_eq!(
+      pages_sql("odd", false),
+      "(COALESCE((archives.pages % 2 = $1), FALSE)"
+    );
+    assert_eq!(
+      pages_sql("EVEN|20", false),
+      "(COALESCE((archives.pages % 2 = $1 OR archives.pages = $2), FALSE)"
+    );
+    assert_eq!(
+      pages_sql("abc|10", false),
+      "(COALESCE((archives.pages = $1), FALSE)"
+    );
+
+    for invalid in ["abc", "-", "1-x", "99999", ""] {
+      assert_eq!(pages_sql(invalid, false), "(TRUE", "{invalid}");
+    }
+  }
+
+  fn tags(tags: &[(&str, &str)]) -> Vec<(String, String)> {
+    tags
+      .iter()
+      .map(|(name, namespace)| (name.to_string(), namespace.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn disallowed_namespaces_are_skipped() {
+    let metadata = Metadata {
+      allowed_namespaces: Some(vec!["female".to_string(), "misc".to_string()]),
+      ..Default::default()
+    };
+
+    let (kept, disallowed) = filter_tag_namespaces(
+      tags(&[("a", "Females"), ("b", "male"), ("c", "other"), ("d", "")]),
+      &metadata,
+    );
+
+    assert_eq!(kept, tags(&[("a", "female"), ("c", "misc")]));
+    assert_eq!(disallowed, tags(&[("b", "male"), ("d", "")]));
+  }
+
+  #[test]
+  fn disallowed_namespaces_are_coerced_to_the_fallback() {
+    let metadata = Metadata {
+      allowed_namespaces: Some(vec!["female".to_string(), "misc".to_string()]),
+      namespace_fallback: Some("misc".to_string()),
+      ..Default::default()
+    };
+
+    let (kept, disallowed) = filter_tag_namespaces(
+      tags(&[("a", "female"), ("b", "male"), ("c", "")]),
+      &metadata,
+    );
+
+    assert_eq!(kept, tags(&[("a", "female"), ("b", "misc"), ("c", "misc")]));
+    assert_eq!(disallowed, tags(&[("b", "male"), ("c", "")]));
+  }
+
+  #[test]
+  fn every_namespace_is_allowed_by_default() {
+    let all = tags(&[("a", "Female"), ("b", "anything")]);
+    let (kept, disallowed) = filter_tag_namespaces(all.clone(), &Metadata::default());
+
+    assert_eq!(kept, all);
+    assert!(disallowed.is_empty());
+  }
+
+  #[sqlx::test]
+  async fn archive_hash_conflicts_with_deleted_archives(pool: PgPool) {
+    let id = insert(archive("First"), &pool).await;
+    let deleted = insert(archive("Second"), &pool).await;
+    soft_delete_archives(&[deleted], &pool).await.unwrap();
+
+    let hash: String = sqlx::query_scalar("SELECT hash FROM archives WHERE id = $1")
+      .bind(deleted)
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+
+    assert!(matches!(
+      set_archive_hash(id, &hash, &pool).await,
+      Err(DbError::Conflict {
+        archive_id: Some(archive_id),
+        ..
+      }) if archive_id == deleted
+    ));
+
+    set_archive_hash(id, &"a".repeat(64), &pool).await.unwrap();
+  }
+
+  #[sqlx::test]
+  async fn distinct_languages_are_counted(pool: PgPool) {
+    for (title, language) in [
+      ("First", Some("English")),
+      ("Second", Some("English")),
+      ("Third", Some("Japanese")),
+      ("Seventh", Some("english")),
+      ("Fourth", None),
+      ("Fifth", None),
+      ("Sixth", None),
+    ] {
+      insert(
+        UpsertArchiveData {
+          language: language.map(|language| language.to_string()),
+          ..archive(title)
+        },
+        &pool,
+      )
+      .await;
+    }
+
+    let deleted = insert(
+      UpsertArchiveData {
+        language: Some("Chinese".to_string()),
+        ..archive("Deleted")
+      },
+      &pool,
+    )
+    .await;
+    soft_delete_archives(&[deleted], &pool).await.unwrap();
+
+    let languages = |languages: &[(&str, i64)]| {
+      languages
+        .iter()
+        .map(|(language, count)| (language.to_string(), *count))
+        .collect_vec()
+    };
+
+    assert_eq!(
+      distinct_languages(false, &pool).await.unwrap(),
+      languages(&[("English", 3), ("Japanese", 1)])
+    );
+    assert_eq!(
+      distinct_languages(true, &pool).await.unwrap(),
+      languages(&[("English", 3), ("none", 3), ("Japanese", 1)])
+    );
+  }
+
+  #[sqlx::test]
+  async fn publisher_matches_rank_above_tag_matches(pool: PgPool) {
+    // Inserted first so a tie would list it last, as ties are ordered by descending id
+    let publisher = insert(
+      UpsertArchiveData {
+        publishers: Some(vec!["Acme".to_string()]),
+        ..archive("First")
+      },
+      &pool,
+    )
+    .await;
+    let tag = insert(
+      UpsertArchiveData {
+        tags: Some(vec![("Acme".to_string(), "misc".to_string())]),
+        ..archive("Second")
+      },
+      &pool,
+    )
+    .await;
+
+    let query = SearchQuery {
+      sort: Sorting::Relevance,
+      order: Ordering::Desc,
+      ..search_query("acme")
+    };
+
+    let ids = search(&query, &pool)
+      .await
+      .unwrap()
+      .archives
+      .into_iter()
+      .map(|archive| archive.id)
+      .collect_vec();
+
+    assert_eq!(ids, vec![publisher, tag]);
+  }
+
+  #[sqlx::test]
+  async fn excluded_namespaces_only_match_explicit_filters(pool: PgPool) {
+    let meta = insert(
+      UpsertArchiveData {
+        tags: Some(vec![("Needs Review".to_string(), "meta".to_string())]),
+        ..archive("First")
+      },
+      &pool,
+    )
+    .await;
+    insert(archive("Second"), &pool).await;
+
+    assert_eq!(search_ids("review", &pool).await, vec![meta]);
+
+    // Stored at startup from `search.excluded_namespaces`, which is empty in tests
+    sqlx::query("INSERT INTO fts_excluded_namespaces (namespace) VALUES ('meta')")
+      .execute(&pool)
+      .await
+      .unwrap();
+
+    rebuild_fts(None, &pool).await.unwrap();
+
+    assert_eq!(search_ids("review", &pool).await, Vec::<i64>::new());
+    assert_eq!(search_ids("tag:needs-review", &pool).await, vec![meta]);
+
+    // Newly indexed archives leave them out through the triggers
+    let other = insert(
+      UpsertArchiveData {
+        tags: Some(vec![
+          ("Needs Review".to_string(), "meta".to_string()),
+          ("Glasses".to_string(), "female".to_string()),
+        ]),
+        ..archive("Third")
+      },
+      &pool,
+    )
+    .await;
+
+    assert_eq!(search_ids("review", &pool).await, Vec::<i64>::new());
+    assert_eq!(search_ids("glasses", &pool).await, vec![other]);
+    assert_eq!(
+      search_ids("tag:needs-review", &pool).await,
+      vec![meta, other]
+    );
+  }
+
+  #[sqlx::test]
+  async fn page_far_beyond_the_results_is_empty(pool: PgPool) {
+    for title in ["First", "Second", "Third"] {
+      insert(archive(title), &pool).await;
+    }
+
+    let query = SearchQuery {
+      page: 100_000,
+      ..search_query("")
+    };
+    let results = search(&query, &pool).await.unwrap();
+
+    assert_eq!(results.total, 3);
+    assert!(!results.capped);
+    assert!(results.archives.is_empty());
+  }
+
+  #[sqlx::test]
+  async fn phrases_match_wildcards_literally(pool: PgPool) {
+    let percent = insert(archive("100% Love"), &pool).await;
+    insert(archive("1000 Nights"), &pool).await;
+    let underscore = insert(archive("snake_case"), &pool).await;
+    insert(archive("snake-case"), &pool).await;
+
+    assert_eq!(search_ids(r#""100%""#, &pool).await, vec![percent]);
+    assert_eq!(search_ids(r#""snake_case""#, &pool).await, vec![underscore]);
+  }
+
+  #[sqlx::test]
+  async fn relevance_lists_the_best_title_match_first(pool: PgPool) {
+    insert(
+      UpsertArchiveData {
+        tags: Some(vec![
+          ("Dragon".to_string(), "misc".to_string()),
+          ("Quest".to_string(), "misc".to_string()),
+        ]),
+        ..archive("First")
+      },
+      &pool,
+    )
+    .await;
+    let title = insert(archive("Dragon Quest"), &pool).await;
+    insert(
+      UpsertArchiveData {
+        parodies: Some(vec!["Dragon Quest".to_string()]),
+        ..archive("Third")
+      },
+      &pool,
+    )
+    .await;
+    insert(archive("Unrelated"), &pool).await;
+
+    for descending in [false, true] {
+      let query = SearchQuery {
+        sort: Sorting::Relevance,
+        order: if descending {
+          Ordering::Desc
+        } else {
+          Ordering::Asc
+        },
+        ..search_query("dragon quest")
+      };
+
+      let results = search(&query, &pool).await.unwrap();
+
+      assert_eq!(results.total, 3, "descending: {descending}");
+      assert_eq!(results.archives[0].id, title, "descending: {descending}");
+    }
+  }
+
+  /// Data for a new archive with a page for each filename.
+  fn archive_with_images(title: &str, filenames: &[&str]) -> UpsertArchiveData {
+    UpsertArchiveData {
+      pages: Some(filenames.len() as i16),
+      images: Some(
+        filenames
+          .iter()
+          .enumerate()
+          .map(|(i, filename)| ArchiveImage {
+            filename: filename.to_string(),
+            page_number: i as i16 + 1,
+            ..Default::default()
+          })
+          .collect(),
+      ),
+      ..archive(title)
+    }
+  }
+
+  async fn page_numbers(archive_id: i64, pool: &PgPool) -> Vec<(String, i16)> {
+    sqlx::query_as(
+      "SELECT filename, page_number FROM archive_images WHERE archive_id = $1 ORDER BY filename",
+    )
+    .bind(archive_id)
+    .fetch_all(pool)
+    .await
+    .unwrap()
+  }
+
+  #[sqlx::test]
+  async fn swapping_pages_keeps_the_cover(pool: PgPool) {
+    let id = insert(
+      archive_with_images("Pages", &["1.jpg", "2.jpg", "3.jpg"]),
+      &pool,
+    )
+    .await;
+
+    reorder_pages(
+      id,
+      vec![("1.jpg".to_string(), 2), ("2.jpg".to_string(), 1)],
+      &pool,
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+      page_numbers(id, &pool).await,
+      vec![
+        ("1.jpg".to_string(), 2),
+        ("2.jpg".to_string(), 1),
+        ("3.jpg".to_string(), 3)
+      ]
+    );
+
+    let (pages, thumbnail): (i16, i16) =
+      sqlx::query_as("SELECT pages, thumbnail FROM archives WHERE id = $1")
+        .bind(id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+    assert_eq!(pages, 3);
+    assert_eq!(thumbnail, 2);
+  }
+
+  #[sqlx::test]
+  async fn suggested_tokens_follow_the_namespace_keyword(pool: PgPool) {
+    insert(
+      UpsertArchiveData {
+        tags: Some(vec![
+          ("Glasses".to_string(), "female".to_string()),
+          ("Gloves".to_string(), "male".to_string()),
+        ]),
+        ..archive("First")
+      },
+      &pool,
+    )
+    .await;
+
+    assert_eq!(
+      suggest_query_tokens("female:gl", 10, &pool).await.unwrap(),
+      vec!["female:glasses"]
+    );
+    assert_eq!(
+      suggest_query_tokens("-male:gl", 10, &pool).await.unwrap(),
+      vec!["-male:gloves"]
+    );
+    assert_eq!(
+      suggest_query_tokens("gl", 10, &pool).await.unwrap(),
+      vec!["tag:glasses", "tag:gloves"]
+    );
+  }
+
+  #[sqlx::test]
+  async fn blacklist_excludes_tags_in_the_namespace(pool: PgPool) {
+    let female = insert(
+      UpsertArchiveData {
+        tags: Some(vec![("Guro".to_string(), "female".to_string())]),
+        ..archive("First")
+      },
+      &pool,
+    )
+    .await;
+    let male = insert(
+      UpsertArchiveData {
+        tags: Some(vec![("Guro".to_string(), "male".to_string())]),
+        ..archive("Second")
+      },
+      &pool,
+    )
+    .await;
+
+    let blacklisted = |blacklist: &[&str]| SearchQuery {
+      blacklist: blacklist.iter().map(|entry| entry.to_string()).collect(),
+      ..search_query("")
+    };
+    let ids = |query: SearchQuery| {
+      let pool = pool.clone();
+
+      async move {
+        search(&query, &pool)
+          .await
+          .unwrap()
+          .archives
+          .into_iter()
+          .map(|archive| archive.id)
+          .sorted()
+          .collect_vec()
+      }
+    };
+
+    assert_eq!(ids(blacklisted(&["female:guro"])).await, vec![male]);
+    assert_eq!(ids(blacklisted(&["male:guro"])).await, vec![female]);
+    assert_eq!(ids(blacklisted(&["tag:guro"])).await, Vec::<i64>::new());
+  }
+
+  #[sqlx::test]
+  async fn failed_batch_is_rolled_back(pool: PgPool) {
+    let failing = UpsertArchiveData {
+      path: Some("/content/failing.zip".to_string()),
+      ..Default::default()
+    };
+
+    let err = upsert_archives(
+      vec![archive("First"), failing, archive("Third")],
+      &pool,
+      &MultiProgress::new(),
+    )
+    .await
+    .unwrap_err()
+    .to_string();
+
+    assert!(err.contains("index 1"), "{err}");
+    assert!(err.contains("/content/failing.zip"), "{err}");
+
+    let archives: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM archives")
+      .fetch_one(&pool)
+      .await
+      .unwrap();
+    assert_eq!(archives, 0);
+
+    let ids = upsert_archives(
+      vec![archive("First"), archive("Second")],
+      &pool,
+      &MultiProgress::new(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(search_ids("", &pool).await, ids);
+  }
+
+  #[test]
+  fn tag_namespaces_are_bound() {
+    let sql = tag_matches_sql("female:foo -other:bar");
+
+    assert!(!sql.contains("'female'"), "{sql}");
+    assert!(!sql.contains("'misc'"), "{sql}");
+    assert_eq!(sql.matches("namespace ILIKE $").count(), 4, "{sql}");
+  }
+
+  #[sqlx::test]
+  async fn recomputing_pages_clamps_only_set_covers(pool: PgPool) {
+    let without_alt = insert(
+      archive_with_images("First", &["1.jpg", "2.jpg", "3.jpg"]),
+      &pool,
+    )
+    .await;
+    let with_alt = insert(
+      UpsertArchiveData {
+        thumbnail: Some(3),
+        alt_thumbnail: Some(3),
+        ..archive_with_images("Second", &["1.jpg", "2.jpg", "3.jpg"])
+      },
+      &pool,
+    )
+    .await;
+
+    sqlx::query("DELETE FROM archive_images WHERE page_number = 3")
+      .execute(&pool)
+      .await
+      .unwrap();
+
+    assert_eq!(recompute_page_counts(&pool).await.unwrap(), 2);
+
+    let covers = |id: i64| {
+      sqlx::query_as::<_, (i16, i16, Option<i16>)>(
+        "SELECT pages, thumbnail, alt_thumbnail FROM archives WHERE id = $1",
+      )
+      .bind(id)
+      .fetch_one(&pool)
+    };
+
+    assert_eq!(covers(without_alt).await.unwrap(), (2, 1, None));
+    assert_eq!(covers(with_alt).await.unwrap(), (2, 2, Some(2)));
+  }
+
+  #[sqlx::test]
+  async fn artist_sort_lists_archives_without_artists_last(pool: PgPool) {
+    // Every tenth archive has no artist, the rest share 100 artists
+    sqlx::query(
+      r#"INSERT INTO archives (slug, title, path, hash, pages, size, released_at)
+      SELECT 'archive-' || i, 'Archive ' || i, '/content/' || i || '.zip', LPAD(i::text, 64, '0'), 10, 1024, NOW()
+      FROM generate_series(1, 3000) i"#,
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+      r#"INSERT INTO artists (name, slug)
+      SELECT 'Artist ' || LPAD(i::text, 3, '0'), 'artist-' || LPAD(i::text, 3, '0')
+      FROM generate_series(0, 99) i"#,
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+      r#"INSERT INTO archive_artists (archive_id, artist_id)
+      SELECT archives.id, artists.id FROM archives
+      INNER JOIN artists ON artists.name = 'Artist ' || LPAD((archives.id % 100)::text, 3, '0')
+      WHERE archives.id % 10 <> 0"#,
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+
+    let artists: Vec<(i64, Option<String>)> = sqlx::query_as(
+      r#"SELECT archives.id, artists.name FROM archives
+      LEFT JOIN archive_artists ON archive_artists.archive_id = archives.id
+      LEFT JOIN artists ON artists.id = archive_artists.artist_id"#,
+    )
+    .fetch_all(&pool)
+    .await
+    .unwrap();
+
+    let page = |page: usize, descending: bool| {
+      let query = SearchQuery {
+        page,
+        sort: Sorting::Artist,
+        order: if descending {
+          Ordering::Desc
+        } else {
+          Ordering::Asc
+        },
+        ..search_query("")
+      };
+      let pool = pool.clone();
+
+      async move {
+        let start = std::time::Instant::now();
+        let results = search(&query, &pool).await.unwrap();
+
+        assert!(start.elapsed().as_secs() < 5, "{:?}", start.elapsed());
+        assert_eq!(results.total, 3000);
+
+        results
+          .archives
+          .into_iter()
+          .map(|archive| archive.id)
+          .collect_vec()
+      }
+    };
+
+    for descending in [false, true] {
+      let expected = artists
+        .iter()
+        .sorted_by(|(a_id, a_name), (b_id, b_name)| {
+          let order = match (a_name, b_name) {
+            (Some(a), Some(b)) => a.cmp(b).then(a_id.cmp(b_id)),
+            (Some(_), None) => return std::cmp::Ordering::Less,
+            (None, Some(_)) => return std::cmp::Ordering::Greater,
+            (None, None) => a_id.cmp(b_id),
+          };
+
+          if descending {
+            order.reverse()
+          } else {
+            order
+          }
+        })
+        .map(|(id, _)| *id)
+        .collect_vec();
+
+      let last = page(125, descending).await;
+
+      assert_eq!(page(1, descending).await, expected[..24]);
+      assert_eq!(last, expected[2976..]);
+      assert!(last.iter().all(|id| id % 10 == 0));
+    }
+  }
+
+  #[sqlx::test]
+  async fn duplicating_an_archive_copies_its_relations(pool: PgPool) {
+    let id = insert(
+      UpsertArchiveData {
+        tags: Some(tags(&[("glasses", "female"), ("full color", "")])),
+        sources: Some(vec![ArchiveSource {
+          name: "Example".to_string(),
+          url: Some("https://example.com/g/1".to_string()),
+        }]),
+        ..archive_with_images("Original", &["01.jpg", "02.jpg", "03.jpg"])
+      },
+      &pool,
+    )
+    .await;
+
+    let copy = duplicate_archive(id, "f".repeat(64), "/content/copy.zip".to_string(), &pool)
+      .await
+      .unwrap();
+
+    assert_ne!(copy, id);
+
+    let archives: Vec<(i64, String, String, Option<NaiveDateTime>)> =
+      sqlx::query_as(r#"SELECT id, slug, path, deleted_at FROM archives ORDER BY id"#)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+    assert_eq!(archives.len(), 2);
+    assert_eq!(archives[0].0, id);
+    assert!(archives[0].3.is_none());
+    assert_eq!(archives[1].0, copy);
+    assert_eq!(archives[1].2, "/content/copy.zip");
+    assert_ne!(archives[0].1, archives[1].1);
+
+    for sql in [
+      r#"SELECT tags.name || ':' || archive_tags.namespace FROM archive_tags
+      INNER JOIN tags ON tags.id = archive_tags.tag_id WHERE archive_id = $1 ORDER BY 1"#,
+      r#"SELECT name || ':' || url FROM archive_sources WHERE archive_id = $1 ORDER BY 1"#,
+      r#"SELECT page_number || ':' || filename FROM archive_images
+      WHERE archive_id = $1 ORDER BY page_number"#,
+    ] {
+      let relations = |archive_id: i64| {
+        sqlx::query_scalar::<_, String>(sql)
+          .bind(archive_id)
+          .fetch_all(&pool)
+      };
+      let original = relations(id).await.unwrap();
+
+      assert!(!original.is_empty(), "{sql}");
+      assert_eq!(relations(copy).await.unwrap(), original, "{sql}");
+    }
+  }
+
+  #[sqlx::test]
+  async fn excluded_namespaces_are_only_stored_when_writing(pool: PgPool) {
+    let stored = || {
+      sqlx::query_scalar::<_, String>(
+        r#"SELECT namespace FROM fts_excluded_namespaces ORDER BY namespace"#,
+      )
+      .fetch_all(&pool)
+    };
+    let namespaces = ["meta".to_string(), "Misc".to_string()];
+
+    sync_excluded_namespaces(&namespaces, false, &pool)
+      .await
+      .unwrap();
+    assert!(stored().await.unwrap().is_empty());
+
+    sync_excluded_namespaces(&namespaces, true, &pool)
+      .await
+      .unwrap();
+    assert_eq!(stored().await.unwrap(), ["meta", "misc"]);
+
+    sync_excluded_namespaces(&[], false, &pool).await.unwrap();
+    assert_eq!(stored().await.unwrap(), ["meta", "misc"]);
+  }
 }
@@ -20,6 +20,7 @@ async fn run() -> anyhow::Result<()> {
   match &cli.command {
     Some(command) => {
       crate::log::cli_logging();
+      config::validate_config()?;
 
       match command {
         Commands::Index(args) => cmd::index(args.clone()).await?,
@@ -29,6 +30,19 @@ async fn run() -> anyhow::Result<()> {
         Commands::Scrape(args) => cmd::scrape(args.clone()).await?,
         Commands::Publish(args) => cmd::pusblish(args.clone(), true).await?,
         Commands::Unpublish(args) => cmd::pusblish(args.clone(), false).await?,
+        Commands::AddTags(args) => cmd::bulk_tags(args.clone(), true).await?,
+        Commands::RemoveTags(args) => cmd::bulk_tags(args.clone(), false).await?,
+        Commands::NormalizeNamespaces => cmd::normalize_namespaces().await?,
+        Commands::ReassignNamespace(args) => cmd::reassign_namespace(args.clone()).await?,
+        Commands::CollapseSources => cmd::collapse_sources().await?,
+        Commands::FillHashes => cmd::fill_hashes().await?,
+        Commands::RecomputePages => cmd::recompute_pages().await?,
+        Commands::RebuildFts(args) => cmd::rebuild_fts(args.clone()).await?,
+        Commands::SetCover(args) => cmd::set_cover(args.clone()).await?,
+        Commands::RegenerateSlug(args) => cmd::regenerate_slug(args.clone()).await?,
+        Commands::FindSource(args) => cmd::find_source(args.clone()).await?,
+        Commands::Duplicate(args) => cmd::duplicate(args.clone()).await?,
+        Commands::Export(args) => cmd::export(args.clone()).await?,
       }
     }
     None => api::start_server().await?,
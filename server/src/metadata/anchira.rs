@@ -1,6 +1,5 @@
 use crate::{db, utils};
 use serde::Deserialize;
-use slug::slugify;
 
 #[derive(Deserialize)]
 pub struct Metadata {
@@ -34,7 +33,7 @@ pub struct Metadata {
 
 pub fn add_metadata(info: Metadata, archive: &mut db::UpsertArchiveData) -> anyhow::Result<()> {
   archive.title = Some(info.title);
-  archive.slug = archive.title.as_ref().map(slugify);
+  archive.slug = archive.title.as_ref().map(utils::slugify);
   archive.description = info.description;
   archive.thumbnail = info.thumbnail;
   archive.released_at = utils::map_timestamp(info.released);
@@ -2,7 +2,6 @@ use crate::{config::CONFIG, db, utils};
 use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
 use funty::Fundamental;
 use serde::Deserialize;
-use slug::slugify;
 use std::collections::HashMap;
 
 #[derive(Deserialize, Debug)]
@@ -31,7 +30,7 @@ pub fn add_metadata(info: Metadata, archive: &mut db::UpsertArchiveData) -> anyh
   } else {
     Some(info.title)
   };
-  archive.slug = archive.title.as_ref().map(slugify);
+  archive.slug = archive.title.as_ref().map(utils::slugify);
   archive.thumbnail = Some(1);
   archive.language = info.language;
 
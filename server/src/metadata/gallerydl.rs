@@ -1,7 +1,6 @@
 use crate::{config::CONFIG, db, utils};
 use chrono::NaiveDateTime;
 use serde::Deserialize;
-use slug::slugify;
 
 #[derive(Deserialize)]
 pub struct Metadata {
@@ -26,7 +25,7 @@ pub fn add_metadata(info: Metadata, archive: &mut db::UpsertArchiveData) -> anyh
   } else {
     Some(info.title)
   };
-  archive.slug = archive.title.as_ref().map(slugify);
+  archive.slug = archive.title.as_ref().map(utils::slugify);
   archive.thumbnail = Some(1);
   archive.language = info.language.map(|s| utils::capitalize_words(&s));
   archive.released_at = info
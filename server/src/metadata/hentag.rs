@@ -2,7 +2,6 @@ use crate::{config::CONFIG, db, utils};
 use chrono::DateTime;
 use itertools::Itertools;
 use serde::Deserialize;
-use slug::slugify;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Metadata {
@@ -33,7 +32,7 @@ pub fn add_metadata(info: Metadata, archive: &mut db::UpsertArchiveData) -> anyh
   } else {
     Some(info.title)
   };
-  archive.slug = archive.title.as_ref().map(slugify);
+  archive.slug = archive.title.as_ref().map(utils::slugify);
   archive.thumbnail = Some(1);
   archive.language = info.language.map(|s| utils::capitalize_words(&s));
   archive.released_at = info
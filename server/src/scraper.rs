@@ -4,6 +4,7 @@ use indicatif::MultiProgress;
 use serde::Deserialize;
 use sqlx::PgPool;
 use std::path::Path;
+use tracing::info;
 
 #[derive(Copy, Clone, ValueEnum, Deserialize)]
 #[clap(rename_all = "lower")]
@@ -13,7 +14,12 @@ pub enum ScrapeSite {
 
 const HENTAG_API: &str = "https://hentag.com/api/v1/search/vault";
 
-async fn scrape_hentag(id: i64, pool: &PgPool, mp: &MultiProgress) -> anyhow::Result<()> {
+async fn scrape_hentag(
+  id: i64,
+  dry_run: bool,
+  pool: &PgPool,
+  mp: &MultiProgress,
+) -> anyhow::Result<()> {
   let sources = sqlx::query!(
     "SELECT name, url FROM archive_sources WHERE archive_id = $1",
     id
@@ -48,7 +54,13 @@ async fn scrape_hentag(id: i64, pool: &PgPool, mp: &MultiProgress) -> anyhow::Re
     data.has_metadata = Some(true);
 
     hentag::add_metadata(info.clone(), &mut data)?;
-    db::upsert_archive(data, pool, mp).await?;
+
+    if dry_run {
+      let outcome = db::preview_upsert_archive(&data, pool).await?;
+      mp.suspend(|| info!(target: "scraper::dry_run", "Archive ID {id}: {outcome:?}"));
+    } else {
+      db::upsert_archive(data, pool, mp).await?;
+    }
   }
 
   Ok(())
@@ -57,11 +69,12 @@ async fn scrape_hentag(id: i64, pool: &PgPool, mp: &MultiProgress) -> anyhow::Re
 pub async fn scrape(
   id: i64,
   site: ScrapeSite,
+  dry_run: bool,
   pool: &PgPool,
   mp: &MultiProgress,
 ) -> anyhow::Result<()> {
   match site {
-    ScrapeSite::HenTag => scrape_hentag(id, pool, mp).await?,
+    ScrapeSite::HenTag => scrape_hentag(id, dry_run, pool, mp).await?,
   };
 
   Ok(())
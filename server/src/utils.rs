@@ -1,19 +1,25 @@
+use crate::config::CONFIG;
 use anyhow::anyhow;
 use async_zip::ZipString;
-use chrono::{DateTime, NaiveDateTime};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use funty::Numeric;
 use itertools::Itertools;
+use once_cell::sync::Lazy;
 use regex::Regex;
 use ring::digest::{Context, Digest, SHA256};
+use serde::Deserialize;
 use sqlx::{Postgres, QueryBuilder};
 use std::{
+  collections::HashMap,
   ffi::OsStr,
   fs,
   io::Read,
   os,
   path::{Path, PathBuf},
+  sync::RwLock,
 };
 use time::OffsetDateTime;
+use tracing::warn;
 
 pub fn sha256_digest<R: Read>(mut reader: R) -> anyhow::Result<Digest> {
   let mut context = Context::new(&SHA256);
@@ -121,6 +127,23 @@ pub fn parse_source_name(str: &str) -> String {
   }
 }
 
+/// Reduces a source URL to the part that identifies it, so `https://www.site.com/g/1/` and
+/// `http://site.com/g/1` are the same URL.
+pub fn normalize_source_url(url: &str) -> String {
+  let url = url.trim();
+  let url = match url.find("://") {
+    Some(index) => &url[index + 3..],
+    None => url,
+  };
+  let url = url
+    .get(..4)
+    .filter(|prefix| prefix.eq_ignore_ascii_case("www."))
+    .map(|_| &url[4..])
+    .unwrap_or(url);
+
+  url.trim_end_matches('/').to_string()
+}
+
 pub fn is_image<S: AsRef<str>>(filename: S) -> bool {
   let filename = filename.as_ref();
 
@@ -205,28 +228,153 @@ pub fn parse_zip_date(date: zip::DateTime) -> NaiveDateTime {
     .naive_utc()
 }
 
+/// Canonical tag names by slug. Built-in, extended by the `metadata.tag_aliases` file.
+static TAG_ALIASES: Lazy<RwLock<HashMap<String, String>>> = Lazy::new(|| {
+  let aliases = load_tag_aliases().unwrap_or_else(|err| {
+    warn!("{err}. Using the default tag aliases");
+    default_tag_aliases()
+  });
+
+  RwLock::new(aliases)
+});
+
+fn default_tag_aliases() -> HashMap<String, String> {
+  [
+    ("fff-threesome", "FFF Threesome"),
+    ("fffm-foursome", "FFFM Foursome"),
+    ("ffm-threesome", "FFM Threesome"),
+    ("fft-threesome", "FFT Threesome"),
+    ("mmf-threesome", "MMF Threesome"),
+    ("mmm-threesome", "MMM Threesome"),
+    ("mmmf-foursome", "MMMF Foursome"),
+    ("mmt-threesome", "MMT Threesome"),
+    ("cg-set", "CG Set"),
+    ("bss", "BSS"),
+    ("bl", "BL"),
+    ("comics-r18", "Comics R18"),
+    ("sci-fi", "Sci-Fi"),
+    ("x-ray", "X-ray"),
+    ("non-h", "Non-H"),
+    ("sixty-nine", "Sixty-Nine"),
+  ]
+  .into_iter()
+  .map(|(slug, name)| (slug.to_string(), name.to_string()))
+  .collect()
+}
+
+/// Reads the default aliases along with the ones of the configured file, which take precedence.
+/// The file maps slugs to names, as JSON when it has a `.json` extension and TOML otherwise.
+fn load_tag_aliases() -> anyhow::Result<HashMap<String, String>> {
+  let mut aliases = default_tag_aliases();
+
+  let Some(path) = &CONFIG.metadata.tag_aliases else {
+    return Ok(aliases);
+  };
+
+  let contents = fs::read_to_string(path).map_err(|err| {
+    anyhow!(
+      "Couldn't read the tag aliases file '{}': {err}",
+      path.display()
+    )
+  })?;
+
+  let file: HashMap<String, String> = if path.extension().and_then(OsStr::to_str) == Some("json") {
+    serde_json::from_str(&contents).map_err(|err| err.to_string())
+  } else {
+    toml::from_str(&contents).map_err(|err| err.message().to_string())
+  }
+  .map_err(|err| anyhow!("Invalid tag aliases file '{}': {err}", path.display()))?;
+
+  aliases.extend(file);
+
+  Ok(aliases)
+}
+
+/// Loads the tag aliases again, so changes to the aliases file apply without a restart.
+/// Returns the amount of aliases. The current ones are kept if the file can't be loaded.
+pub fn reload_tag_aliases() -> anyhow::Result<usize> {
+  let aliases = load_tag_aliases()?;
+  let count = aliases.len();
+
+  *TAG_ALIASES.write().unwrap() = aliases;
+
+  Ok(count)
+}
+
 pub fn tag_alias(name: &str, slug: &str) -> String {
-  match slug {
-    "fff-threesome" => "FFF Threesome".to_string(),
-    "fffm-foursome" => "FFFM Foursome".to_string(),
-    "ffm-threesome" => "FFM Threesome".to_string(),
-    "fft-threesome" => "FFT Threesome".to_string(),
-    "mmf-threesome" => "MMF Threesome".to_string(),
-    "mmm-threesome" => "MMM Threesome".to_string(),
-    "mmmf-foursome" => "MMMF Foursome".to_string(),
-    "mmt-threesome" => "MMT Threesome".to_string(),
-    "cg-set" => "CG Set".to_string(),
-    "bss" => "BSS".to_string(),
-    "bl" => "BL".to_string(),
-    "comics-r18" => "Comics R18".to_string(),
-    "sci-fi" => "Sci-Fi".to_string(),
-    "x-ray" => "X-ray".to_string(),
-    "non-h" => "Non-H".to_string(),
-    "sixty-nine" => "Sixty-Nine".to_string(),
-    _ => name.to_string(),
+  TAG_ALIASES
+    .read()
+    .unwrap()
+    .get(slug)
+    .cloned()
+    .unwrap_or_else(|| name.to_string())
+}
+
+/// Maps a tag namespace to its canonical form.
+///
+/// Canonical namespaces are `male`, `female`, `misc` and the empty string for tags without a
+/// namespace. Known synonyms are mapped to those, anything else is only lowercased.
+/// Namespaces targeted by `search.tag_keywords` are kept as they are, so they can be searched.
+pub fn normalize_namespace(namespace: &str) -> String {
+  let namespace = namespace.trim().to_lowercase();
+
+  if CONFIG
+    .search
+    .tag_keywords
+    .values()
+    .any(|target| *target == namespace)
+  {
+    return namespace;
+  }
+
+  match namespace.as_str() {
+    "male" | "males" | "m" | "man" | "men" => "male".to_string(),
+    "female" | "females" | "f" | "woman" | "women" => "female".to_string(),
+    "misc" | "miscellaneous" | "other" | "others" | "mixed" => "misc".to_string(),
+    _ => namespace,
   }
 }
 
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlugStrategy {
+  /// Transliterates to ASCII. Non-Latin text may end up empty.
+  #[default]
+  Ascii,
+  /// Keeps Unicode letters and numbers, only dropping URL-unsafe characters.
+  Unicode,
+}
+
+pub fn slugify<S: AsRef<str>>(s: S) -> String {
+  let s = s.as_ref();
+
+  match CONFIG.metadata.slug_strategy {
+    SlugStrategy::Ascii => slug::slugify(s),
+    SlugStrategy::Unicode => {
+      let slug = s
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect::<String>()
+        .split('-')
+        .filter(|s| !s.is_empty())
+        .join("-");
+
+      if slug.is_empty() {
+        slug::slugify(s)
+      } else {
+        slug
+      }
+    }
+  }
+}
+
+/// Seed for random orders that changes once a day (UTC), so everyone gets the same shuffle for
+/// the whole day.
+pub fn daily_seed() -> String {
+  Utc::now().format("%Y-%m-%d").to_string()
+}
+
 pub fn map_timestamp(timestamp: Option<i64>) -> Option<NaiveDateTime> {
   timestamp
     .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
@@ -234,37 +382,19 @@ pub fn map_timestamp(timestamp: Option<i64>) -> Option<NaiveDateTime> {
 }
 
 pub fn create_symlink(src: &impl AsRef<Path>, dest: &impl AsRef<Path>) -> anyhow::Result<()> {
-  #[cfg(unix)]
-  if let Err(err) = os::unix::fs::symlink(src, dest) {
-    match err.kind() {
-      std::io::ErrorKind::AlreadyExists => {
-        if fs::remove_file(dest).is_ok() {
-          if let Err(err) = os::unix::fs::symlink(src, dest) {
-            return Err(anyhow!("Couldn't create a symbolic link: {err}"));
-          }
-        }
-      }
-      _ => {
-        return Err(anyhow!("Couldn't create a symbolic link: {err}"));
-      }
-    }
+  // Anything already at the destination is replaced, including links to a stale location
+  if fs::symlink_metadata(dest).is_ok() {
+    fs::remove_file(dest)
+      .map_err(|err| anyhow!("Couldn't replace the existing symbolic link: {err}"))?;
   }
 
+  #[cfg(unix)]
+  os::unix::fs::symlink(src, dest)
+    .map_err(|err| anyhow!("Couldn't create a symbolic link: {err}"))?;
+
   #[cfg(windows)]
-  if let Err(err) = os::windows::fs::symlink_file(src, dest) {
-    match err.kind() {
-      std::io::ErrorKind::AlreadyExists => {
-        if fs::remove_file(dest).is_ok() {
-          if let Err(err) = os::windows::fs::symlink(src, dest) {
-            return Err(anyhow!("Couldn't create a symbolic link: {err}"));
-          }
-        }
-      }
-      _ => {
-        return Err(anyhow!("Couldn't create a symbolic link: {err}"));
-      }
-    }
-  }
+  os::windows::fs::symlink_file(src, dest)
+    .map_err(|err| anyhow!("Couldn't create a symbolic link: {err}"))?;
 
   Ok(())
 }